@@ -0,0 +1,100 @@
+#[macro_use] extern crate bencher;
+extern crate dmap;
+
+use bencher::Bencher;
+use dmap::{DmapItem, DmapValue, MergePolicy, Parser};
+use dmap::value::ItemName;
+
+const TRACKS: usize = 50_000;
+
+fn item<'a>(name: &'static str, value: DmapValue<'a, 'static>) -> DmapItem<'a, 'static> {
+    DmapItem { name: ItemName::Name(name), value }
+}
+
+// roughly what iTunes sends for /databases/1/items
+fn listing<'a>(titles: &'a [String]) -> DmapItem<'a, 'static> {
+    let tracks = titles.iter().enumerate().map(|(i, title)| item("dmap.listingitem", DmapValue::Container(vec![
+        item("dmap.itemkind", DmapValue::I8(2)),
+        item("daap.songdatakind", DmapValue::I8(0)),
+        item("dmap.itemid", DmapValue::I32(i as i32)),
+        item("dmap.persistentid", DmapValue::I64(i as i64 * 7919)),
+        item("dmap.itemname", DmapValue::String(title.into())),
+        item("daap.songartist", DmapValue::String("Some Artist".into())),
+        item("daap.songalbum", DmapValue::String("Some Album".into())),
+        item("daap.songtime", DmapValue::I32(215_000)),
+        item("daap.songtracknumber", DmapValue::I16((i % 20) as i16)),
+        item("daap.songyear", DmapValue::I16(2017)),
+        item("daap.songuserrating", DmapValue::I8(60)),
+    ]))).collect();
+
+    item("daap.databasesongs", DmapValue::Container(vec![
+        item("dmap.status", DmapValue::I32(200)),
+        item("dmap.updatetype", DmapValue::I8(0)),
+        item("dmap.specifiedtotalcount", DmapValue::I32(titles.len() as i32)),
+        item("dmap.returnedcount", DmapValue::I32(titles.len() as i32)),
+        item("dmap.listing", DmapValue::Container(tracks)),
+    ]))
+}
+
+fn titles() -> Vec<String> {
+    (0..TRACKS).map(|i| format!("Track number {}", i)).collect()
+}
+
+fn decode_items(b: &mut Bencher) {
+    let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+    let titles = titles();
+    let data = dmap::to_vec(&parser, &listing(&titles)).unwrap();
+    b.bytes = data.len() as u64;
+    b.iter(|| {
+        let item: DmapItem = dmap::from_slice(&parser, &data).unwrap();
+        item
+    });
+}
+
+fn encode_items(b: &mut Bencher) {
+    let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+    let titles = titles();
+    let listing = listing(&titles);
+    b.bytes = dmap::to_vec(&parser, &listing).unwrap().len() as u64;
+    b.iter(|| dmap::to_vec(&parser, &listing).unwrap());
+}
+
+fn write_items(b: &mut Bencher) {
+    let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+    let titles = titles();
+    let listing = listing(&titles);
+    b.bytes = dmap::to_vec(&parser, &listing).unwrap().len() as u64;
+    b.iter(|| dmap::to_writer(&parser, std::io::sink(), &listing).unwrap());
+}
+
+// every code of the dictionary once, real listings use codes from all over it
+fn all_codes(parser: &Parser) -> Vec<[u8; 4]> {
+    parser.content_codes().map(|c| c.code().0).collect()
+}
+
+fn lookup_indexed(b: &mut Bencher) {
+    let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+    let codes = all_codes(&parser);
+    b.iter(|| codes.iter().map(|c| parser.lookup_code(*c).unwrap().kind() as u16).sum::<u16>());
+}
+
+// what every lookup cost before the dictionary was indexed
+fn lookup_linear(b: &mut Bencher) {
+    let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+    let codes = all_codes(&parser);
+    b.iter(|| codes.iter().map(|c| {
+        parser.content_codes().find(|x| x.code().0 == *c).unwrap().kind() as u16
+    }).sum::<u16>());
+}
+
+fn merge_dictionary(b: &mut Bencher) {
+    let server = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+    b.iter(|| {
+        let mut parser = Parser::bootstrap();
+        parser.merge(&server, MergePolicy::Override);
+        parser
+    });
+}
+
+benchmark_group!(benches, decode_items, encode_items, write_items, lookup_indexed, lookup_linear, merge_dictionary);
+benchmark_main!(benches);
@@ -0,0 +1,86 @@
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+const CODES: &str = "data/content-codes.txt";
+
+fn type_kind(name: &str) -> Option<&'static str> {
+    Some(match name {
+        "i8" => "I8",
+        "u8" => "U8",
+        "i16" => "I16",
+        "u16" => "U16",
+        "i32" => "I32",
+        "u32" => "U32",
+        "i64" => "I64",
+        "u64" => "U64",
+        "string" => "String",
+        "timestamp" => "Timestamp",
+        "version" => "Version",
+        "container" => "Container",
+        _ => return None,
+    })
+}
+
+// codes are plain ascii except for the odd \xNN escape, same syntax as
+// `Code`'s FromStr
+fn parse_code(s: &str) -> Option<Vec<u8>> {
+    let mut code = Vec::new();
+    let mut rest = s.as_bytes();
+    while let Some(&first) = rest.first() {
+        if rest.starts_with(b"\\x") {
+            let hex = rest.get(2..4).filter(|h| h.iter().all(u8::is_ascii_hexdigit))?;
+            code.push(u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap());
+            rest = &rest[4..];
+        } else if first.is_ascii_graphic() && first != b'\\' {
+            code.push(first);
+            rest = &rest[1..];
+        } else {
+            return None;
+        }
+    }
+    if code.len() == 4 { Some(code) } else { None }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", CODES);
+
+    let input = BufReader::new(File::open(CODES).expect("failed to open content code list"));
+    let mut codes: Vec<Vec<u8>> = Vec::new();
+    let mut names: Vec<String> = Vec::new();
+
+    let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("builtin_types.rs");
+    let mut out = File::create(out_path).unwrap();
+    writeln!(out, "static BUILTIN_TYPES: &[ContentCode<'static>] = &[").unwrap();
+
+    for (i, line) in input.lines().enumerate() {
+        let line = line.unwrap();
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let fail = |msg: &str| -> ! { panic!("{}:{}: {}", CODES, i + 1, msg) };
+        if fields.len() != 3 {
+            fail("expected <code> <name> <type>");
+        }
+        let code = parse_code(fields[0]).unwrap_or_else(|| fail("invalid code"));
+        let name = fields[1];
+        let kind = type_kind(fields[2]).unwrap_or_else(|| fail("invalid type"));
+        if codes.contains(&code) {
+            fail("duplicate code");
+        }
+        if names.iter().any(|n| n == name) {
+            fail("duplicate name");
+        }
+
+        writeln!(out, "    ContentCode {{ code: {:?}, name: {:?}, kind: TypeKind::{} }},",
+                 code, name, kind).unwrap();
+        codes.push(code);
+        names.push(name.to_string());
+    }
+
+    writeln!(out, "];").unwrap();
+}
@@ -0,0 +1,284 @@
+//! `#[derive(DmapEncode, DmapDecode)]` for the `dmap` crate, see `dmap::typed`.
+//!
+//! ```ignore
+//! #[derive(DmapEncode, DmapDecode)]
+//! #[dmap(code = "mlog")]
+//! struct Login {
+//!     #[dmap(code = "mstt", kind = "i32")]
+//!     status: i32,
+//!     #[dmap(code = "mlid", kind = "i32")]
+//!     session_id: u32,
+//!     #[dmap(skip)]
+//!     local_only: bool,
+//! }
+//! ```
+//!
+//! Skipped fields are not encoded and decode to `Default::default()`. A field
+//! type that doesn't fit its `kind` is a compile error.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{Data, DeriveInput, Fields, GenericParam, Ident, LitStr, Type};
+
+#[proc_macro_derive(DmapEncode, attributes(dmap))]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    expand_encode(&input).unwrap_or_else(compile_error).into()
+}
+
+#[proc_macro_derive(DmapDecode, attributes(dmap))]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    expand_decode(&input).unwrap_or_else(compile_error).into()
+}
+
+// `syn::Error::into_compile_error` uses `::core`, which 2015 crates don't have
+fn compile_error(err: syn::Error) -> TokenStream2 {
+    err.into_iter().map(|e| {
+        let msg = e.to_string();
+        quote_spanned!(e.span()=> compile_error!(#msg);)
+    }).collect()
+}
+
+struct Field<'a> {
+    ident: &'a Ident,
+    ty: &'a Type,
+    /// `None` for `#[dmap(skip)]`
+    tag: Option<([u8; 4], Ident)>,
+}
+
+struct Struct<'a> {
+    code: [u8; 4],
+    fields: Vec<Field<'a>>,
+}
+
+const KINDS: &[(&str, &str)] = &[
+    ("i8", "I8"), ("u8", "U8"), ("i16", "I16"), ("u16", "U16"), ("i32", "I32"), ("u32", "U32"),
+    ("i64", "I64"), ("u64", "U64"), ("string", "String"), ("timestamp", "Timestamp"),
+    ("version", "Version"), ("container", "Container"),
+];
+
+fn parse(input: &DeriveInput) -> syn::Result<Struct<'_>> {
+    let fields = match input.data {
+        Data::Struct(ref s) => match s.fields {
+            Fields::Named(ref f) => &f.named,
+            _ => return Err(syn::Error::new_spanned(&input.ident, "only structs with named fields are supported")),
+        },
+        _ => return Err(syn::Error::new_spanned(&input.ident, "only structs are supported")),
+    };
+
+    let code = match attributes(&input.attrs)? {
+        (Some(code), None, false) => code,
+        (None, None, false) => return Err(syn::Error::new_spanned(&input.ident, "missing #[dmap(code = \"...\")]")),
+        _ => return Err(syn::Error::new_spanned(&input.ident, "a struct only takes #[dmap(code = \"...\")]")),
+    };
+
+    let mut parsed: Vec<Field> = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let tag = match attributes(&field.attrs)? {
+            (None, None, true) => None,
+            (Some(code), Some(kind), false) => {
+                if let Some(other) = parsed.iter().find(|f| f.tag.as_ref().is_some_and(|t| t.0 == code)) {
+                    let msg = format!("code used twice, also by `{}`", other.ident);
+                    return Err(syn::Error::new_spanned(ident, msg));
+                }
+                Some((code, kind))
+            }
+            _ => return Err(syn::Error::new_spanned(ident, "expected #[dmap(code = \"...\", kind = \"...\")] or #[dmap(skip)]")),
+        };
+        parsed.push(Field { ident, ty: &field.ty, tag });
+    }
+    Ok(Struct { code, fields: parsed })
+}
+
+// (code, kind, skip)
+fn attributes(attrs: &[syn::Attribute]) -> syn::Result<(Option<[u8; 4]>, Option<Ident>, bool)> {
+    let (mut code, mut kind, mut skip) = (None, None, false);
+    for attr in attrs.iter().filter(|a| a.path().is_ident("dmap")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("code") {
+                let lit: LitStr = meta.value()?.parse()?;
+                code = Some(parse_code(&lit.value()).ok_or_else(|| meta.error("invalid content code"))?);
+            } else if meta.path.is_ident("kind") {
+                let lit: LitStr = meta.value()?.parse()?;
+                let variant = KINDS.iter().find(|k| k.0 == lit.value()).ok_or_else(|| meta.error("unknown kind"))?.1;
+                kind = Some(Ident::new(variant, Span::call_site()));
+            } else if meta.path.is_ident("skip") {
+                skip = true;
+            } else {
+                return Err(meta.error("unknown dmap attribute"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok((code, kind, skip))
+}
+
+// same syntax as `dmap::Code`'s FromStr: ASCII, or \xNN escapes
+fn parse_code(s: &str) -> Option<[u8; 4]> {
+    let mut code = Vec::new();
+    let mut rest = s.as_bytes();
+    while let Some(&first) = rest.first() {
+        if rest.starts_with(b"\\x") {
+            let hex = rest.get(2..4).filter(|h| h.iter().all(u8::is_ascii_hexdigit))?;
+            code.push(u8::from_str_radix(std::str::from_utf8(hex).unwrap(), 16).unwrap());
+            rest = &rest[4..];
+        } else if first.is_ascii_graphic() && first != b'\\' {
+            code.push(first);
+            rest = &rest[1..];
+        } else {
+            return None;
+        }
+    }
+    let mut buf = [0; 4];
+    if code.len() != 4 {
+        return None;
+    }
+    buf.copy_from_slice(&code);
+    Some(buf)
+}
+
+fn code_tokens(code: &[u8; 4]) -> TokenStream2 {
+    let [a, b, c, d] = *code;
+    quote!([#a, #b, #c, #d])
+}
+
+fn expand_encode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let parsed = parse(input)?;
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let code = code_tokens(&parsed.code);
+    let checks = parsed.fields.iter().filter_map(|f| {
+        let (_, ref kind) = *f.tag.as_ref()?;
+        let ty = f.ty;
+        Some(quote_spanned!(ty.span()=> ::dmap::typed::encode_as::<::dmap::typed::kinds::#kind, #ty>();))
+    });
+    let fields = parsed.fields.iter().filter_map(|f| {
+        let (ref code, ref kind) = *f.tag.as_ref()?;
+        let (ident, code) = (f.ident, code_tokens(code));
+        Some(quote! {
+            ::dmap::typed::EncodeField::encode_field(
+                &self.#ident, writer, ::dmap::Code(#code), ::dmap::TypeKind::#kind)?;
+        })
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::dmap::typed::DmapEncode for #name #ty_generics #where_clause {
+            const CODE: [u8; 4] = #code;
+
+            fn encode_fields(&self, writer: &mut ::dmap::writer::Writer) -> ::std::result::Result<(), ::dmap::Error> {
+                #(#checks)*
+                #(#fields)*
+                Ok(())
+            }
+        }
+
+        impl #impl_generics ::dmap::typed::EncodeAs<::dmap::typed::kinds::Container> for #name #ty_generics #where_clause {}
+
+        impl #impl_generics ::dmap::typed::EncodeField for #name #ty_generics #where_clause {
+            fn encode_field(&self, writer: &mut ::dmap::writer::Writer, code: ::dmap::Code, kind: ::dmap::TypeKind)
+                -> ::std::result::Result<(), ::dmap::Error>
+            {
+                if kind != ::dmap::TypeKind::Container {
+                    return Err(::dmap::Error::new(::dmap::ErrorKind::WrongKind(kind)));
+                }
+                writer.begin_container(code)?;
+                ::dmap::typed::DmapEncode::encode_fields(self, writer)?;
+                writer.end_container()?;
+                Ok(())
+            }
+        }
+    })
+}
+
+fn expand_decode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let parsed = parse(input)?;
+    let name = &input.ident;
+
+    // a struct may borrow from the input through its one lifetime
+    let mut lifetimes = input.generics.lifetimes();
+    let (impl_lifetime, ty_lifetime, de) = match (lifetimes.next(), lifetimes.next()) {
+        (None, _) => {
+            let de = syn::Lifetime::new("'de", Span::call_site());
+            (quote!(<#de>), quote!(), de)
+        }
+        (Some(l), None) => {
+            let l = &l.lifetime;
+            (quote!(<#l>), quote!(<#l>), l.clone())
+        }
+        (Some(_), Some(l)) => return Err(syn::Error::new_spanned(l, "at most one lifetime is supported")),
+    };
+    if let Some(p) = input.generics.params.iter().find(|p| !matches!(p, GenericParam::Lifetime(_))) {
+        return Err(syn::Error::new_spanned(p, "generic structs are not supported"));
+    }
+
+    let code = code_tokens(&parsed.code);
+    let checks = parsed.fields.iter().filter_map(|f| {
+        let (_, ref kind) = *f.tag.as_ref()?;
+        let ty = f.ty;
+        Some(quote_spanned!(ty.span()=> ::dmap::typed::decode_as::<::dmap::typed::kinds::#kind, #ty>();))
+    });
+    let slots = parsed.fields.iter().filter(|f| f.tag.is_some()).map(|f| {
+        let (ident, ty) = (f.ident, f.ty);
+        quote!(let mut #ident: ::std::option::Option<#ty> = None;)
+    });
+    let arms = parsed.fields.iter().filter_map(|f| {
+        let (ref code, ref kind) = *f.tag.as_ref()?;
+        let (ident, ty, code) = (f.ident, f.ty, code_tokens(code));
+        Some(quote! {
+            #code => <#ty as ::dmap::typed::DecodeField<#de>>::merge(&mut #ident, tag, ::dmap::TypeKind::#kind)?,
+        })
+    });
+    let values = parsed.fields.iter().map(|f| {
+        let (ident, ty) = (f.ident, f.ty);
+        if f.tag.is_none() {
+            return quote!(#ident: ::std::default::Default::default(),);
+        }
+        let field_name = ident.to_string();
+        quote! {
+            #ident: match #ident.or_else(<#ty as ::dmap::typed::DecodeField<#de>>::missing) {
+                Some(v) => v,
+                None => return Err(::dmap::typed::missing_field(#field_name)),
+            },
+        }
+    });
+
+    Ok(quote! {
+        impl #impl_lifetime ::dmap::typed::DmapDecode<#de> for #name #ty_lifetime {
+            const CODE: [u8; 4] = #code;
+
+            fn decode_fields(fields: ::dmap::typed::Fields<'_, #de>) -> ::std::result::Result<Self, ::dmap::Error> {
+                #(#checks)*
+                #(#slots)*
+                for tag in fields {
+                    let tag = tag?;
+                    match tag.code().0 {
+                        #(#arms)*
+                        _ => {}
+                    }
+                }
+                Ok(#name {
+                    #(#values)*
+                })
+            }
+        }
+
+        impl #impl_lifetime ::dmap::typed::DecodeAs<::dmap::typed::kinds::Container> for #name #ty_lifetime {}
+
+        impl #impl_lifetime ::dmap::typed::DecodeField<#de> for #name #ty_lifetime {
+            fn decode_field(tag: ::dmap::typed::Tag<'_, #de>, kind: ::dmap::TypeKind)
+                -> ::std::result::Result<Self, ::dmap::Error>
+            {
+                <Self as ::dmap::typed::DmapDecode<#de>>::decode_fields(tag.children(kind)?)
+            }
+        }
+    })
+}
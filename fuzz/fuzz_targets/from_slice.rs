@@ -0,0 +1,11 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate dmap;
+
+use dmap::{DecodeOptions, DmapItem, Limits, Parser};
+
+fuzz_target!(|data: &[u8]| {
+    let mut parser = Parser::builtin();
+    parser.set_decode_options(DecodeOptions { limits: Limits::untrusted(), ..DecodeOptions::default() });
+    let _ = dmap::from_slice::<DmapItem>(&parser, data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate dmap;
+
+use dmap::Parser;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Parser::try_new(data);
+});
@@ -0,0 +1,18 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate dmap;
+
+use dmap::{DecodeOptions, DmapItem, Limits, Parser};
+
+// whatever decodes must encode again and decode to the same thing
+fuzz_target!(|data: &[u8]| {
+    let mut parser = Parser::builtin();
+    parser.set_decode_options(DecodeOptions { limits: Limits::untrusted(), ..DecodeOptions::default() });
+    let item = match dmap::from_slice::<DmapItem>(&parser, data) {
+        Ok(item) => item,
+        Err(_) => return,
+    };
+    let encoded = dmap::to_vec(&parser, &item).expect("decoded item doesn't encode");
+    let decoded = dmap::from_slice::<DmapItem>(&parser, &encoded).expect("encoded item doesn't decode");
+    assert_eq!(item, decoded);
+});
@@ -0,0 +1,111 @@
+//! Command line tool to look at DMAP files.
+
+extern crate dmap;
+extern crate serde_json;
+
+use dmap::Parser;
+use dmap::dump::{dump, DumpOptions};
+use dmap::json::{to_json, from_json};
+use serde_json::Value;
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::process;
+
+const USAGE: &str = "\
+usage: dmap [options] <command> <file>
+
+commands:
+    dump <file>         print a tree of all tags
+    to-json <file>      convert to JSON (see the dmap::json docs for the format)
+    from-json <file>    convert JSON back to DMAP (written to stdout)
+    codes <file>        list the content codes in a /content-codes reply
+
+options:
+    -c, --codes <file>  content codes to use instead of the builtin ones
+    --max-depth <n>     dump: don't show containers nested deeper than this
+    --max-items <n>     dump: show at most this many children per container
+
+<file> can be - for stdin.";
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut codes = None;
+    let mut options = DumpOptions::default();
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-c" | "--codes" => codes = Some(args.next().unwrap_or_else(|| usage())),
+            "--max-depth" => options.max_depth = Some(number(args.next())),
+            "--max-items" => options.max_items = Some(number(args.next())),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 2 {
+        usage();
+    }
+
+    let codes = codes.map(|f| read(&f));
+    let parser = match codes {
+        Some(ref c) => Parser::try_new(c).unwrap_or_else(|e| fail(&e)),
+        None => Parser::builtin(),
+    };
+    let input = read(&positional[1]);
+
+    // write through one locked handle, and stop quietly when the reader
+    // goes away (`dmap dump x | head`)
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let result = match &positional[0][..] {
+        "dump" => out.write_all(dump(&parser, &input, &options).as_bytes()),
+        "to-json" => {
+            let json = to_json(&parser, &input).unwrap_or_else(|e| fail(&e));
+            writeln!(out, "{}", serde_json::to_string_pretty(&json).unwrap())
+        }
+        "from-json" => {
+            let json: Value = serde_json::from_slice(&input).unwrap_or_else(|e| fail(&e));
+            let data = from_json(&json).unwrap_or_else(|e| fail(&e));
+            out.write_all(&data)
+        }
+        "codes" => {
+            let codes = Parser::try_new(&input).unwrap_or_else(|e| fail(&e));
+            let result = codes.content_codes().try_for_each(|c| writeln!(out, "{}", c));
+            result
+        }
+        _ => usage(),
+    };
+    match result.and_then(|_| out.flush()) {
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+        Err(e) => fail(&e),
+        Ok(()) => {}
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2)
+}
+
+fn fail(e: &dyn std::fmt::Display) -> ! {
+    eprintln!("dmap: {}", e);
+    process::exit(1)
+}
+
+fn number(arg: Option<String>) -> usize {
+    arg.and_then(|n| n.parse().ok()).unwrap_or_else(|| usage())
+}
+
+fn read(file: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    let result = if file == "-" {
+        io::stdin().read_to_end(&mut data)
+    } else {
+        File::open(file).and_then(|mut f| f.read_to_end(&mut data))
+    };
+    result.unwrap_or_else(|e| fail(&format!("{}: {}", file, e)));
+    data
+}
@@ -1,26 +1,31 @@
-use super::{Parser, ContentCode, TypeKind};
+use super::{Parser, ContentCode, TypeKind, Error, ErrorKind, DecodeOptions, StringPolicy, read_int, read_str};
+use value::VALUE_TOKEN;
 use byteorder::{BigEndian, ByteOrder};
-use serde::de::{self, Error as ErrorTrait, Visitor, DeserializeSeed, IntoDeserializer, Deserialize};
-use serde::de::value::{BorrowedBytesDeserializer, BorrowedStrDeserializer, MapAccessDeserializer};
-use std::{str, mem};
+use serde::de::{self, Visitor, DeserializeSeed, Deserialize};
+use serde::de::value::{BorrowedBytesDeserializer, BorrowedStrDeserializer, MapAccessDeserializer, U32Deserializer};
+use std::borrow::Cow;
+use std::str;
 
 pub struct MapDeserializer<'a, 'k: 'a, 'de>  {
     parser: &'a Parser<'k>,
     current: Option<RawMessage<'a, 'k, 'de>>,
     tail: &'de [u8],
+    offset: usize,
+    depth: usize,
+    items: usize,
 }
 
 struct RawMessage<'a, 'k: 'a, 'de> {
     typedesc: Result<&'a ContentCode<'k>, &'de [u8]>,
     body: &'de [u8],
+    offset: usize,
 }
-type Error = de::value::Error;
 
 impl<'a, 'k: 'a + 'de, 'de: 'a> RawMessage<'a, 'k, 'de> {
     fn code(&self) -> [u8; 4] {
-        let code = match &self.typedesc {
-            &Ok(ref c) => &c.code,
-            &Err(x) => x,
+        let code = match self.typedesc {
+            Ok(c) => &c.code,
+            Err(x) => x,
         };
         let mut buf = [0; 4];
         buf.copy_from_slice(code);
@@ -34,6 +39,21 @@ impl<'a, 'k: 'a + 'de, 'de> MapDeserializer<'a, 'k, 'de> {
             parser,
             tail: input,
             current: None,
+            offset: 0,
+            depth: 0,
+            items: 0,
+        }
+    }
+
+    /// The children of the container `body` at `offset`.
+    fn nested(&self, body: &'de [u8], offset: usize) -> MapDeserializer<'a, 'k, 'de> {
+        MapDeserializer {
+            parser: self.parser,
+            tail: body,
+            current: None,
+            offset: offset + 8,
+            depth: self.depth + 1,
+            items: self.items,
         }
     }
 
@@ -41,24 +61,36 @@ impl<'a, 'k: 'a + 'de, 'de> MapDeserializer<'a, 'k, 'de> {
         Ok(match self.current.take() {
             Some(x) => Some(x),
             None if !self.tail.is_empty() => {
-                let err = Error::custom("Failed to get message (truncated input?)");
-                let input = self.tail;
-                let mut code = [0; 4];
-                let code_ref = input.get(0..4).ok_or(err.clone())?;
-                code.copy_from_slice(code_ref);
-                let size = BigEndian::read_u32(input.get(4..8).ok_or(err.clone())?) as usize;
-                let body = input.get(8..size+8).ok_or(err)?;
-                self.tail = &input[8+size..];
-                let typedesc = self.parser.types.iter().find(|x| x.code == code).ok_or(code_ref);
-                Some(RawMessage { typedesc, body })
+                let (input, offset) = (self.tail, self.offset);
+                let (code, body) = read_header(input, offset)?;
+                self.tail = &input[8 + body.len()..];
+                self.offset += 8 + body.len();
+                let typedesc = self.parser.lookup_code(code).ok_or(&input[..4]);
+                self.items += 1;
+                self.parser.decode_options().limits
+                    .check(typedesc.ok().map(|c| c.kind), body.len(), self.depth, self.items)
+                    .map_err(|e| e.at(offset, code))?;
+                Some(RawMessage { typedesc, body, offset })
             }
             None => None,
         })
     }
 }
 
+/// Splits the tag at the start of `input` into its code and body.
+///
+/// `offset` is only used for error messages.
+pub(crate) fn read_header(input: &[u8], offset: usize) -> Result<([u8; 4], &[u8]), Error> {
+    let truncated = || Error::new(ErrorKind::Truncated).at_offset(offset);
+    let mut code = [0; 4];
+    code.copy_from_slice(input.get(0..4).ok_or_else(truncated)?);
+    let truncated = || Error::new(ErrorKind::Truncated).at(offset, code);
+    let size = BigEndian::read_u32(input.get(4..8).ok_or_else(truncated)?) as usize;
+    let body = input.get(8..).and_then(|b| b.get(..size)).ok_or_else(truncated)?;
+    Ok((code, body))
+}
 
-impl<'de, 'a, 'k: 'a + 'de, 'b> de::MapAccess<'de> for &'b mut MapDeserializer<'a, 'k, 'de> {
+impl<'de, 'a, 'k: 'a + 'de> de::MapAccess<'de> for &mut MapDeserializer<'a, 'k, 'de> {
     type Error = Error;
 
     fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
@@ -70,7 +102,7 @@ impl<'de, 'a, 'k: 'a + 'de, 'b> de::MapAccess<'de> for &'b mut MapDeserializer<'
         };
 
         let name = msg.typedesc.map(|c| c.name);
-        println!("yielding {:?}", name);
+        trace_tag!(msg.code(), name.ok(), msg.offset);
         self.current = Some(msg);
 
         match name {
@@ -93,36 +125,100 @@ impl<'a: 'b, 'k: 'a + 'de, 'de: 'b, 'b> de::Deserializer<'de> for ValueDeseriali
 
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
-        byte_buf unit unit_struct newtype_struct tuple
-        tuple_struct map struct enum identifier ignored_any
+        byte_buf unit unit_struct tuple
+        tuple_struct identifier ignored_any
     }
 
     fn deserialize_any<V>(self, v: V) -> Result<V::Value, Self::Error>
         where V: Visitor<'de>
     {
-        let RawMessage { typedesc, body } = self.0.current.take().unwrap();
-        println!("dany {:?}", typedesc);
-        //println!("{:?} {}", typedesc, body.len());
-        match typedesc {
+        let msg = self.0.current.take().unwrap();
+        let (code, offset) = (msg.code(), msg.offset);
+        let RawMessage { typedesc, body, .. } = msg;
+        let DecodeOptions { lengths, strings, .. } = self.0.parser.decode_options();
+        let int = |kind| read_int(kind, body, lengths);
+        let result = match typedesc {
             Ok(c) => match c.kind {
-                TypeKind::I8 => v.visit_i8(body[0] as i8),
-                TypeKind::U8 => v.visit_u8(body[0]),
-                TypeKind::I16 => v.visit_i16(BigEndian::read_i16(body)),
-                TypeKind::U16 => v.visit_u16(BigEndian::read_u16(body)),
-                TypeKind::I32 => v.visit_i32(BigEndian::read_i32(body)),
-                TypeKind::U32 | TypeKind::Timestamp | TypeKind::Version /*fixme*/
-                    => v.visit_u32(BigEndian::read_u32(body)),
-                TypeKind::I64 => v.visit_i64(BigEndian::read_i64(body)),
-                TypeKind::U64 => v.visit_u64(BigEndian::read_u64(body)),
-                TypeKind::String => v.visit_borrowed_str(str::from_utf8(body).map_err(|_| Error::custom("invalid utf8 lul"))?), // FIXME
-                TypeKind::Container => v.visit_map(&mut MapDeserializer {
-                    parser: self.0.parser,
-                    current: None,
-                    tail: body,
+                TypeKind::I8 => int(c.kind).and_then(|x| v.visit_i8(x as i8)),
+                TypeKind::U8 => int(c.kind).and_then(|x| v.visit_u8(x as u8)),
+                TypeKind::I16 => int(c.kind).and_then(|x| v.visit_i16(x as i16)),
+                TypeKind::U16 => int(c.kind).and_then(|x| v.visit_u16(x as u16)),
+                TypeKind::I32 => int(c.kind).and_then(|x| v.visit_i32(x as i32)),
+                TypeKind::U32 | TypeKind::Timestamp | TypeKind::Version
+                    => int(c.kind).and_then(|x| v.visit_u32(x as u32)),
+                TypeKind::I64 => int(c.kind).and_then(|x| v.visit_i64(x as i64)),
+                TypeKind::U64 => int(c.kind).and_then(|x| v.visit_u64(x)),
+                TypeKind::String => read_str(body, strings).and_then(|s| match s {
+                    Some(Cow::Borrowed(s)) => v.visit_borrowed_str(s),
+                    Some(Cow::Owned(s)) => v.visit_string(s),
+                    None => v.visit_borrowed_bytes(body),
                 }),
+                TypeKind::Container => {
+                    trace_container!(code, c.name, offset);
+                    let mut nested = self.0.nested(body, offset);
+                    let value = v.visit_map(&mut nested).map_err(|e| e.within(c.name));
+                    self.0.items = nested.items;
+                    value
+                }
             },
             Err(_) => v.visit_borrowed_bytes(body),
+        };
+        result.map_err(|e| e.at(offset, code))
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        // DmapValue asks for timestamps, versions and raw strings as enum variants,
+        // everybody else gets a u32 or bytes
+        let options = self.0.parser.decode_options();
+        let variant = match *self.0.current.as_ref().unwrap() {
+            RawMessage { typedesc: Ok(c), .. } if name == VALUE_TOKEN && c.kind == TypeKind::Timestamp => "Timestamp",
+            RawMessage { typedesc: Ok(c), .. } if name == VALUE_TOKEN && c.kind == TypeKind::Version => "Version",
+            RawMessage { typedesc: Ok(c), body, .. } if name == VALUE_TOKEN && c.kind == TypeKind::String
+                && options.strings == StringPolicy::Raw && str::from_utf8(body).is_err() => "InvalidString",
+            _ => return self.deserialize_any(visitor),
+        };
+        let msg = self.0.current.take().unwrap();
+        let result = if variant == "InvalidString" {
+            visitor.visit_enum(TypedValue { variant, value: BorrowedBytesDeserializer::new(msg.body) })
+        } else {
+            read_int(TypeKind::U32, msg.body, options.lengths)
+                .and_then(|value| visitor.visit_enum(TypedValue { variant, value: U32Deserializer::new(value as u32) }))
+        };
+        result.map_err(|e| e.at(msg.offset, msg.code()))
+    }
+
+    fn deserialize_enum<V>(self, _: &'static str, _: &'static [&'static str], visitor: V)
+        -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        // a plain serde enum, stored as the index of a unit variant
+        let (offset, code) = {
+            let msg = self.0.current.as_ref().unwrap();
+            (msg.offset, msg.code())
+        };
+        let index = u32::deserialize(self)?;
+        visitor.visit_enum(U32Deserializer::<Error>::new(index)).map_err(|e| e.at(offset, code))
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        {
+            let msg = self.0.current.as_ref().unwrap();
+            if msg.typedesc.is_err() {
+                return Err(Error::new(ErrorKind::UnknownTag).at(msg.offset, msg.code()));
+            }
         }
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_struct<V>(self, _: &'static str, _: &'static [&'static str], visitor: V)
+        -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        self.deserialize_map(visitor)
     }
 
     fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
@@ -139,6 +235,50 @@ impl<'a: 'b, 'k: 'a + 'de, 'de: 'b, 'b> de::Deserializer<'de> for ValueDeseriali
     }
 }
 
+/// One of the values `DmapValue` asks for as an enum variant, see `VALUE_TOKEN`.
+pub(crate) struct TypedValue<D> {
+    pub(crate) variant: &'static str,
+    pub(crate) value: D,
+}
+
+impl<'de, D: de::Deserializer<'de, Error = Error>> de::EnumAccess<'de> for TypedValue<D> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error>
+        where V: DeserializeSeed<'de>
+    {
+        let variant = seed.deserialize(BorrowedStrDeserializer::new(self.variant))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, D: de::Deserializer<'de, Error = Error>> de::VariantAccess<'de> for TypedValue<D> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Err(de::Error::invalid_type(de::Unexpected::NewtypeVariant, &"unit variant"))
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Self::Error>
+        where T: DeserializeSeed<'de>
+    {
+        seed.deserialize(self.value)
+    }
+
+    fn tuple_variant<V>(self, _: usize, _: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        Err(de::Error::invalid_type(de::Unexpected::NewtypeVariant, &"tuple variant"))
+    }
+
+    fn struct_variant<V>(self, _: &'static [&'static str], _: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        Err(de::Error::invalid_type(de::Unexpected::NewtypeVariant, &"struct variant"))
+    }
+}
+
 struct SeqDeserializer<'a: 'b, 'k: 'a, 'de: 'b, 'b> {
     parent: &'b mut MapDeserializer<'a, 'k, 'de>,
     code: [u8; 4],
@@ -156,8 +296,16 @@ impl<'de, 'a, 'k: 'a + 'de, 'b> de::SeqAccess<'de> for SeqDeserializer<'a, 'k, '
         };
 
         if msg.code() == self.code {
-            seed.deserialize(MapAccessDeserializer::new(
-                &mut MapDeserializer::new(self.parent.parser, msg.body))).map(Some)
+            let (code, offset) = (msg.code(), msg.offset);
+            let name = match msg.typedesc {
+                Ok(c) => c.name,
+                Err(_) => return Err(Error::new(ErrorKind::UnknownTag).at(offset, code)),
+            };
+            trace_container!(code, name, offset);
+            let mut nested = self.parent.nested(msg.body, offset);
+            let value = seed.deserialize(MapAccessDeserializer::new(&mut nested));
+            self.parent.items = nested.items;
+            value.map(Some).map_err(|e| e.within(name).at(offset, code))
         } else {
             self.parent.current = Some(msg);
             Ok(None)
@@ -174,6 +322,6 @@ pub fn from_slice<'a, 'k: 'a + 'de, 'de, T>(parser: &'a Parser<'k>, b: &'de [u8]
     if deserializer.tail.is_empty() {
         Ok(t)
     } else {
-        Err(Error::custom("trailing data"))
+        Err(Error::new(ErrorKind::TrailingData).at_offset(deserializer.offset))
     }
 }
@@ -0,0 +1,116 @@
+/// Declares a C-like enum that is stored as an integer in DMAP.
+///
+/// Fields like `dacp.playerstate` or `dmap.itemkind` hold one of a few
+/// numbers. The enum (de)serializes through its discriminant, and the
+/// serializer writes it as wide as the content code's `TypeKind` says:
+///
+/// ```
+/// #[macro_use] extern crate dmap;
+/// #[macro_use] extern crate serde_derive;
+///
+/// dmap_enum! {
+///     /// `dacp.playerstate`
+///     pub enum PlayerState {
+///         Stopped = 2,
+///         Paused = 3,
+///         Playing = 4,
+///     }
+/// }
+///
+/// #[derive(Serialize, Deserialize)]
+/// struct Status {
+///     #[serde(rename = "dacp.playerstate")]
+///     state: PlayerState,
+/// }
+///
+/// # fn main() {
+/// let parser = dmap::Parser::builtin();
+/// let data = dmap::to_vec(&parser, &Status { state: PlayerState::Playing }).unwrap();
+/// assert_eq!(data, b"caps\0\0\0\x01\x04");
+/// let status: Status = dmap::from_slice(&parser, &data).unwrap();
+/// assert_eq!(status.state, PlayerState::Playing);
+/// # }
+/// ```
+///
+/// The enum derives `Clone`, `Copy`, `Debug`, `PartialEq`, `Eq` and `Hash`.
+/// Other formats see a plain integer.
+///
+/// Enums with a plain `#[derive(Serialize, Deserialize)]` work too, but they
+/// are stored as the variant's index rather than its discriminant.
+#[macro_export]
+macro_rules! dmap_enum {
+    ($(#[$attr:meta])* $vis:vis enum $name:ident { $($(#[$vattr:meta])* $variant:ident = $value:expr, )* }) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        $vis enum $name {
+            $($(#[$vattr])* $variant = $value,)*
+        }
+
+        impl $crate::__serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+                where S: $crate::__serde::Serializer
+            {
+                serializer.serialize_newtype_struct($crate::value::ENUM_TOKEN, &(*self as i64))
+            }
+        }
+
+        $crate::dmap_enum!(@deserialize $name deserialize_i64 { $($variant)* });
+    };
+    // `TypeKind` has always been a plain i16, in any format
+    (@i16 $(#[$attr:meta])* $vis:vis enum $name:ident { $($(#[$vattr:meta])* $variant:ident = $value:expr, )* }) => {
+        $(#[$attr])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        $vis enum $name {
+            $($(#[$vattr])* $variant = $value,)*
+        }
+
+        impl $crate::__serde::Serialize for $name {
+            fn serialize<S>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+                where S: $crate::__serde::Serializer
+            {
+                serializer.serialize_i16(*self as i16)
+            }
+        }
+
+        $crate::dmap_enum!(@deserialize $name deserialize_i16 { $($variant)* });
+    };
+    (@deserialize $name:ident $method:ident { $($variant:ident)* }) => {
+        impl<'de> $crate::__serde::Deserialize<'de> for $name {
+            fn deserialize<D>(deserializer: D) -> ::std::result::Result<Self, D::Error>
+                where D: $crate::__serde::Deserializer<'de>
+            {
+                struct Visitor;
+
+                impl<'de> $crate::__serde::de::Visitor<'de> for Visitor {
+                    type Value = $name;
+
+                    fn expecting(&self, formatter: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                        formatter.write_str(concat!("a ", stringify!($name), " discriminant"))
+                    }
+
+                    fn visit_i64<E>(self, value: i64) -> ::std::result::Result<$name, E>
+                        where E: $crate::__serde::de::Error
+                    {
+                        $(
+                            if value == $name::$variant as i64 {
+                                return Ok($name::$variant);
+                            }
+                        )*
+                        Err(E::custom(format!("unknown {} value: {}", stringify!($name), value)))
+                    }
+
+                    fn visit_u64<E>(self, value: u64) -> ::std::result::Result<$name, E>
+                        where E: $crate::__serde::de::Error
+                    {
+                        match ::std::convert::TryFrom::try_from(value) {
+                            Ok(value) => self.visit_i64(value),
+                            Err(_) => Err(E::custom(format!("unknown {} value: {}", stringify!($name), value))),
+                        }
+                    }
+                }
+
+                deserializer.$method(Visitor)
+            }
+        }
+    };
+}
@@ -0,0 +1,223 @@
+//! Human readable dumps of DMAP data.
+//!
+//! One tag per line, children indented below their container:
+//!
+//! ```text
+//! mlog dmap.loginresponse container [24]
+//!   mstt dmap.status i32 [4] 200
+//!   mlid dmap.sessionid i32 [4] 961664443
+//! ```
+//!
+//! `dump` works on raw bytes and keeps going as far as it can on broken
+//! input, `dump_item` shows a decoded item the same way. `DmapItem` also
+//! implements `Display`, but without a dictionary it can only show the names.
+
+use super::{Parser, Code, TypeKind};
+use lazy::{Children, DmapRef};
+use owned::OwnedDmapItem;
+use value::{DmapItem, DmapValue, ItemName};
+
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DumpOptions {
+    /// Containers nested deeper than this are shown without their children.
+    pub max_depth: Option<usize>,
+    /// Only show this many children per container.
+    pub max_items: Option<usize>,
+    /// Number of bytes shown for tags that are not in the dictionary.
+    pub hex_preview: usize,
+}
+
+impl Default for DumpOptions {
+    fn default() -> DumpOptions {
+        DumpOptions {
+            max_depth: None,
+            max_items: None,
+            hex_preview: 16,
+        }
+    }
+}
+
+/// Dumps all tags in `input`. Broken input ends the dump with a line
+/// describing the error.
+pub fn dump(parser: &Parser, input: &[u8], options: &DumpOptions) -> String {
+    let mut out = String::new();
+    dump_children(&mut out, Children::top_level(parser, input), 0, options).unwrap();
+    out
+}
+
+/// Dumps a decoded item, looking up the codes of its names in `parser`.
+pub fn dump_item(parser: &Parser, item: &DmapItem, options: &DumpOptions) -> String {
+    let mut out = String::new();
+    write_item(&mut out, item, 0, Some(parser), options).unwrap();
+    out
+}
+
+fn dump_children<W: Write>(out: &mut W, children: Children, depth: usize, options: &DumpOptions) -> fmt::Result {
+    let mut count = 0;
+    let mut more = 0;
+    for child in children {
+        let child = match child {
+            Ok(c) => c,
+            Err(e) => return writeln!(out, "{:indent$}! {}", "", e, indent = depth * 2),
+        };
+        if options.max_items.is_some_and(|max| count >= max) {
+            more += 1;
+            continue;
+        }
+        count += 1;
+        write_ref(out, &child, depth, options)?;
+    }
+    more_items(out, depth, more)
+}
+
+fn write_ref<W: Write>(out: &mut W, item: &DmapRef, depth: usize, options: &DumpOptions) -> fmt::Result {
+    line(out, depth, item.code(), item.name(), item.kind(), item.body().len())?;
+    if !item.is_container() {
+        match item.value() {
+            Ok(v) => write_value(out, &v, options)?,
+            Err(_) => write_hex(out, item.body(), options)?,
+        }
+        writeln!(out)
+    } else if options.max_depth.is_some_and(|max| depth >= max) {
+        writeln!(out, " ...")
+    } else {
+        writeln!(out)?;
+        dump_children(out, item.children(), depth + 1, options)
+    }
+}
+
+// without a parser, items with a name are shown without a code
+fn write_item<W: Write>(out: &mut W, item: &DmapItem, depth: usize, parser: Option<&Parser>, options: &DumpOptions)
+    -> fmt::Result
+{
+    let (code, name) = match item.name {
+        ItemName::Name(n) => (parser.map(|p| p.lookup_name(n).map(|c| c.code())), Some(n)),
+        ItemName::Code(c) => (Some(Some(Code(c))), None),
+    };
+    write!(out, "{:indent$}", "", indent = depth * 2)?;
+    match code {
+        Some(Some(code)) => write!(out, "{} ", code)?,
+        // a name that is not in the parser's dictionary
+        Some(None) => write!(out, "???? ")?,
+        None => {}
+    }
+    rest_of_line(out, name, kind(&item.value), encoded_len(&item.value))?;
+
+    let children = match item.value {
+        DmapValue::Container(ref c) => c,
+        ref v => {
+            write_value(out, v, options)?;
+            return writeln!(out);
+        }
+    };
+    if options.max_depth.is_some_and(|max| depth >= max) {
+        return writeln!(out, " ...");
+    }
+    writeln!(out)?;
+    let shown = options.max_items.unwrap_or(children.len()).min(children.len());
+    for child in &children[..shown] {
+        write_item(out, child, depth + 1, parser, options)?;
+    }
+    more_items(out, depth + 1, children.len() - shown)
+}
+
+fn line<W: Write>(out: &mut W, depth: usize, code: Code, name: Option<&str>, kind: Option<TypeKind>, len: usize) -> fmt::Result {
+    write!(out, "{:indent$}{} ", "", code, indent = depth * 2)?;
+    rest_of_line(out, name, kind, len)
+}
+
+fn rest_of_line<W: Write>(out: &mut W, name: Option<&str>, kind: Option<TypeKind>, len: usize) -> fmt::Result {
+    write!(out, "{} ", name.unwrap_or("?"))?;
+    match kind {
+        Some(k) => write!(out, "{}", k)?,
+        None => write!(out, "unknown")?,
+    }
+    write!(out, " [{}]", len)
+}
+
+fn more_items<W: Write>(out: &mut W, depth: usize, more: usize) -> fmt::Result {
+    if more > 0 {
+        writeln!(out, "{:indent$}... {} more", "", more, indent = depth * 2)?;
+    }
+    Ok(())
+}
+
+fn write_value<W: Write>(out: &mut W, value: &DmapValue, options: &DumpOptions) -> fmt::Result {
+    match *value {
+        DmapValue::I8(x) => write!(out, " {}", x),
+        DmapValue::U8(x) => write!(out, " {}", x),
+        DmapValue::I16(x) => write!(out, " {}", x),
+        DmapValue::U16(x) => write!(out, " {}", x),
+        DmapValue::I32(x) => write!(out, " {}", x),
+        DmapValue::U32(x) => write!(out, " {}", x),
+        DmapValue::I64(x) => write!(out, " {}", x),
+        DmapValue::U64(x) => write!(out, " {}", x),
+        DmapValue::Timestamp(x) => write!(out, " {}", x),
+        DmapValue::Version(v) => write!(out, " {}", v),
+        DmapValue::String(ref s) => write!(out, " {:?}", s),
+        DmapValue::InvalidString(b) => write!(out, " {:?}", String::from_utf8_lossy(b)),
+        DmapValue::Unknown(b) => write_hex(out, b, options),
+        DmapValue::Container(_) => Ok(()),
+    }
+}
+
+fn write_hex<W: Write>(out: &mut W, bytes: &[u8], options: &DumpOptions) -> fmt::Result {
+    for b in bytes.iter().take(options.hex_preview) {
+        write!(out, " {:02x}", b)?;
+    }
+    if bytes.len() > options.hex_preview {
+        write!(out, " ...")?;
+    }
+    Ok(())
+}
+
+fn kind(value: &DmapValue) -> Option<TypeKind> {
+    Some(match *value {
+        DmapValue::I8(_) => TypeKind::I8,
+        DmapValue::U8(_) => TypeKind::U8,
+        DmapValue::I16(_) => TypeKind::I16,
+        DmapValue::U16(_) => TypeKind::U16,
+        DmapValue::I32(_) => TypeKind::I32,
+        DmapValue::U32(_) => TypeKind::U32,
+        DmapValue::I64(_) => TypeKind::I64,
+        DmapValue::U64(_) => TypeKind::U64,
+        DmapValue::Timestamp(_) => TypeKind::Timestamp,
+        DmapValue::Version(_) => TypeKind::Version,
+        DmapValue::String(_) | DmapValue::InvalidString(_) => TypeKind::String,
+        DmapValue::Container(_) => TypeKind::Container,
+        DmapValue::Unknown(_) => return None,
+    })
+}
+
+/// Length of the body as it would be encoded.
+fn encoded_len(value: &DmapValue) -> usize {
+    match *value {
+        DmapValue::I8(_) | DmapValue::U8(_) => 1,
+        DmapValue::I16(_) | DmapValue::U16(_) => 2,
+        DmapValue::I32(_) | DmapValue::U32(_) | DmapValue::Timestamp(_) | DmapValue::Version(_) => 4,
+        DmapValue::I64(_) | DmapValue::U64(_) => 8,
+        DmapValue::String(ref s) => s.len(),
+        DmapValue::InvalidString(b) | DmapValue::Unknown(b) => b.len(),
+        DmapValue::Container(ref c) => c.iter().map(|i| 8 + encoded_len(&i.value)).sum(),
+    }
+}
+
+impl<'a, 'k> fmt::Display for DmapItem<'a, 'k> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_item(f, self, 0, None, &DumpOptions::default())
+    }
+}
+
+impl fmt::Display for OwnedDmapItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_borrowed().fmt(f)
+    }
+}
+
+impl<'a, 'k> fmt::Display for DmapRef<'a, 'k> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_ref(f, self, 0, &DumpOptions::default())
+    }
+}
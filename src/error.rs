@@ -0,0 +1,158 @@
+use serde::{de, ser};
+
+use super::{TypeKind, Limit};
+
+use std::{error, fmt, io};
+
+/// Error raised while decoding or encoding DMAP.
+///
+/// Besides the kind of failure it remembers where it happened: the byte offset
+/// of the offending tag, its 4-byte content code and the names of the
+/// containers it was nested in (outermost first).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Error {
+    kind: ErrorKind,
+    offset: Option<usize>,
+    code: Option<[u8; 4]>,
+    path: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The input ended in the middle of a tag.
+    Truncated,
+    /// A string tag did not contain valid UTF-8.
+    InvalidUtf8,
+    /// An integer tag had the wrong number of bytes for its type.
+    InvalidLength { expected: usize, found: usize },
+    /// The input exceeded one of the configured `Limits`.
+    LimitExceeded(Limit),
+    /// There were bytes left after the top level item.
+    TrailingData,
+    /// A tag is not in the dictionary, but its type is needed: it was read
+    /// where a container was expected, or written with a type check.
+    UnknownTag,
+    /// A content name that is not in the dictionary was serialized.
+    UnknownName(String),
+    /// A map key was neither a content name nor a 4-byte content code.
+    KeyNotString,
+    /// The value has a type that can not be represented in DMAP.
+    Unsupported(&'static str),
+    /// A value was written with a type that does not match the dictionary.
+    WrongKind(TypeKind),
+    /// `end_container` without an open container, or a container left open.
+    UnbalancedContainer,
+    /// Reading from or writing to an `io::Read`/`io::Write` failed.
+    Io(io::ErrorKind),
+    /// Any other error, usually raised by a `Deserialize` or `Serialize` impl.
+    Message(String),
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Error {
+        Error {
+            kind,
+            offset: None,
+            code: None,
+            path: Vec::new(),
+        }
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Offset of the offending tag in the input, if known.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// Content code of the offending tag, if known.
+    pub fn code(&self) -> Option<[u8; 4]> {
+        self.code
+    }
+
+    /// Names of the containers the offending tag was nested in.
+    pub fn path(&self) -> &[String] {
+        &self.path
+    }
+
+    // errors bubble up from the innermost tag, so the first location we see wins
+    pub(crate) fn at(mut self, offset: usize, code: [u8; 4]) -> Error {
+        if self.offset.is_none() {
+            self.offset = Some(offset);
+            self.code = Some(code);
+        }
+        self
+    }
+
+    pub(crate) fn at_offset(mut self, offset: usize) -> Error {
+        if self.offset.is_none() {
+            self.offset = Some(offset);
+        }
+        self
+    }
+
+    pub(crate) fn io(e: io::Error) -> Error {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::new(ErrorKind::Truncated),
+            kind => Error::new(ErrorKind::Io(kind)),
+        }
+    }
+
+    pub(crate) fn within(mut self, container: &str) -> Error {
+        self.path.insert(0, container.to_string());
+        self
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::Truncated => f.write_str("truncated input"),
+            ErrorKind::InvalidUtf8 => f.write_str("invalid utf8 in string"),
+            ErrorKind::InvalidLength { expected, found } =>
+                write!(f, "expected {} bytes, found {}", expected, found),
+            ErrorKind::LimitExceeded(limit) => write!(f, "{} limit exceeded", limit),
+            ErrorKind::TrailingData => f.write_str("trailing data"),
+            ErrorKind::UnknownTag => f.write_str("unknown tag"),
+            ErrorKind::UnknownName(ref n) => write!(f, "unknown content name {:?}", n),
+            ErrorKind::KeyNotString => f.write_str("key is not a content name or code"),
+            ErrorKind::Unsupported(what) => write!(f, "{} is not supported", what),
+            ErrorKind::WrongKind(kind) => write!(f, "expected a value of type {}", kind),
+            ErrorKind::UnbalancedContainer => f.write_str("unbalanced container"),
+            ErrorKind::Io(kind) => write!(f, "io error: {:?}", kind),
+            ErrorKind::Message(ref m) => f.write_str(m),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(code) = self.code {
+            write!(f, " in '{}'", String::from_utf8_lossy(&code))?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        if !self.path.is_empty() {
+            write!(f, " (inside {})", self.path.join("/"))?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::new(ErrorKind::Message(msg.to_string()))
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Error {
+        Error::new(ErrorKind::Message(msg.to_string()))
+    }
+}
@@ -0,0 +1,195 @@
+//! Lossless JSON representation of DMAP data.
+//!
+//! Every tag becomes an object:
+//!
+//! ```text
+//! {"code": "mstt", "name": "dmap.status", "type": "i32", "value": 200}
+//! ```
+//!
+//! * `code` is the content code, non-printable bytes escaped as `\xNN`.
+//! * `name` is informational only, it is missing for codes that are not in
+//!   the dictionary.
+//! * `type` is one of `i8`, `u8`, `i16`, `u16`, `i32`, `u32`, `i64`, `u64`,
+//!   `string`, `timestamp`, `version`, `container` or `unknown`.
+//! * `value` is a number for integers and timestamps, a string for strings,
+//!   `"major.minor.patch"` for versions, an array of tags (in order) for
+//!   containers and the base64 encoded body for `unknown` tags.
+//! * Tags whose body doesn't fit their type (a 3 byte `i32`, a string that is
+//!   not UTF-8) have a base64 `raw` body instead of `value`.
+//!
+//! `from_json` only looks at `code`, `type` and `value`/`raw`, so
+//! `from_json(&to_json(..)?)` gives back exactly the input bytes, whatever
+//! dictionary was used. To get a `DmapItem` back, decode those bytes with
+//! `from_slice`.
+
+use serde_json::{Map, Value};
+
+use std::borrow::Cow;
+
+use super::{Parser, Code, TypeKind, Error, ErrorKind, Version, DmapItem};
+use lazy::DmapRef;
+use writer::Writer;
+
+/// Converts the single top level tag in `input`.
+pub fn to_json(parser: &Parser, input: &[u8]) -> Result<Value, Error> {
+    tag_to_json(&DmapRef::new(parser, input)?)
+}
+
+/// Converts a decoded item. `parser` must know all names in it, as for `to_vec`.
+pub fn item_to_json(parser: &Parser, item: &DmapItem) -> Result<Value, Error> {
+    // the mapping is defined on the encoded tags
+    to_json(parser, &::to_vec(parser, item)?)
+}
+
+fn tag_to_json(tag: &DmapRef) -> Result<Value, Error> {
+    let mut map = Map::new();
+    map.insert("code".to_string(), tag.code().to_string().into());
+    if let Some(name) = tag.name() {
+        map.insert("name".to_string(), name.into());
+    }
+    let kind = match tag.kind() {
+        Some(k) => k,
+        None => {
+            map.insert("type".to_string(), "unknown".into());
+            map.insert("value".to_string(), base64(tag.body()).into());
+            return Ok(Value::Object(map));
+        }
+    };
+    map.insert("type".to_string(), kind.to_string().into());
+
+    if kind == TypeKind::Container {
+        let children = tag.children().map(|c| c.and_then(|c| tag_to_json(&c))).collect::<Result<_, _>>();
+        map.insert("value".to_string(), Value::Array(children?));
+        return Ok(Value::Object(map));
+    }
+
+    // lenient lengths would lose the original width
+    let width = kind.width();
+    let value = match tag.value() {
+        Ok(_) if width.is_some_and(|w| w != tag.body().len()) => None,
+        // not valid UTF-8, whatever the string policy made of it
+        Ok(::DmapValue::String(Cow::Owned(_))) | Ok(::DmapValue::InvalidString(_)) => None,
+        Ok(v) => Some(match v {
+            ::DmapValue::I8(x) => x.into(),
+            ::DmapValue::U8(x) => x.into(),
+            ::DmapValue::I16(x) => x.into(),
+            ::DmapValue::U16(x) => x.into(),
+            ::DmapValue::I32(x) => x.into(),
+            ::DmapValue::U32(x) => x.into(),
+            ::DmapValue::I64(x) => x.into(),
+            ::DmapValue::U64(x) => x.into(),
+            ::DmapValue::Timestamp(x) => x.into(),
+            ::DmapValue::Version(v) => v.to_string().into(),
+            ::DmapValue::String(s) => s.into(),
+            ::DmapValue::InvalidString(_) | ::DmapValue::Container(_) | ::DmapValue::Unknown(_) => unreachable!(),
+        }),
+        Err(_) => None,
+    };
+    match value {
+        Some(v) => map.insert("value".to_string(), v),
+        None => map.insert("raw".to_string(), base64(tag.body()).into()),
+    };
+    Ok(Value::Object(map))
+}
+
+/// Converts JSON produced by `to_json` back to DMAP.
+pub fn from_json(json: &Value) -> Result<Vec<u8>, Error> {
+    let mut writer = Writer::new();
+    json_to_tag(&mut writer, json)?;
+    writer.finish()
+}
+
+fn json_to_tag(writer: &mut Writer, json: &Value) -> Result<(), Error> {
+    let tag = json.as_object().ok_or_else(|| invalid("a tag must be an object", json))?;
+    let code: Code = tag.get("code").and_then(Value::as_str).and_then(|c| c.parse().ok())
+        .ok_or_else(|| invalid("missing or invalid code", json))?;
+    let kind = match tag.get("type").and_then(Value::as_str) {
+        Some("unknown") => None,
+        Some(t) => Some(t.parse::<TypeKind>().map_err(|_| invalid("invalid type", json))?),
+        None => return Err(invalid("missing type", json)),
+    };
+
+    if let Some(raw) = tag.get("raw") {
+        let body = raw.as_str().and_then(unbase64).ok_or_else(|| invalid("invalid raw body", json))?;
+        writer.write_bytes(code, &body)?;
+        return Ok(());
+    }
+
+    let value = tag.get("value").ok_or_else(|| invalid("missing value", json))?;
+    let bad = || invalid("value doesn't match the type", json);
+    let int = |min: i64, max: i64| value.as_i64().filter(|x| *x >= min && *x <= max).ok_or_else(bad);
+    match kind {
+        None => writer.write_bytes(code, &value.as_str().and_then(unbase64).ok_or_else(bad)?)?,
+        Some(TypeKind::Container) => {
+            writer.begin_container(code)?;
+            for child in value.as_array().ok_or_else(bad)? {
+                json_to_tag(writer, child)?;
+            }
+            writer.end_container()?
+        }
+        Some(TypeKind::I8) => writer.write_i8(code, int(i8::MIN.into(), i8::MAX.into())? as i8)?,
+        Some(TypeKind::U8) => writer.write_u8(code, int(0, u8::MAX.into())? as u8)?,
+        Some(TypeKind::I16) => writer.write_i16(code, int(i16::MIN.into(), i16::MAX.into())? as i16)?,
+        Some(TypeKind::U16) => writer.write_u16(code, int(0, u16::MAX.into())? as u16)?,
+        Some(TypeKind::I32) => writer.write_i32(code, int(i32::MIN.into(), i32::MAX.into())? as i32)?,
+        Some(TypeKind::U32) | Some(TypeKind::Timestamp) => writer.write_u32(code, int(0, u32::MAX.into())? as u32)?,
+        Some(TypeKind::I64) => writer.write_i64(code, value.as_i64().ok_or_else(bad)?)?,
+        Some(TypeKind::U64) => writer.write_u64(code, value.as_u64().ok_or_else(bad)?)?,
+        Some(TypeKind::Version) => writer.write_u32(code, value.as_str().and_then(parse_version).ok_or_else(bad)?.into())?,
+        Some(TypeKind::String) => writer.write_str(code, value.as_str().ok_or_else(bad)?)?,
+    };
+    Ok(())
+}
+
+fn invalid(what: &str, json: &Value) -> Error {
+    Error::new(ErrorKind::Message(format!("{} in {}", what, json)))
+}
+
+fn parse_version(s: &str) -> Option<Version> {
+    let mut parts = s.split('.');
+    let version = Version {
+        major: parts.next()?.parse().ok()?,
+        minor: parts.next()?.parse().ok()?,
+        patch: parts.next()?.parse().ok()?,
+    };
+    if parts.next().is_some() { None } else { Some(version) }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn unbase64(s: &str) -> Option<Vec<u8>> {
+    let s = s.as_bytes();
+    if s.len() % 4 != 0 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for chunk in s.chunks(4) {
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 {
+            return None;
+        }
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let digit = BASE64.iter().position(|&b| b == c)? as u32;
+            n = n << 6 | digit;
+        }
+        n <<= 6 * padding;
+        out.extend_from_slice(&[(n >> 16) as u8, (n >> 8) as u8, n as u8][..3 - padding]);
+    }
+    Some(out)
+}
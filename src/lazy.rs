@@ -0,0 +1,188 @@
+//! A view of a tag that decodes containers only on demand.
+//!
+//! `DmapValue::Container` decodes a whole subtree up front. `DmapRef` just
+//! remembers where the tag is, so reading the first 20 items of a 50k item
+//! listing only touches those 20.
+
+use super::{Parser, ContentCode, Code, TypeKind, Error, ErrorKind, DecodeOptions, read_int, read_str};
+use de::read_header;
+use value::{DmapItem, DmapValue, ItemName};
+
+use std::str;
+
+#[derive(Clone, Copy)]
+pub struct DmapRef<'a, 'k: 'a> {
+    parser: &'a Parser<'k>,
+    code: [u8; 4],
+    typedesc: Option<&'a ContentCode<'k>>,
+    body: &'a [u8],
+    offset: usize,
+}
+
+impl<'a, 'k: 'a> DmapRef<'a, 'k> {
+    /// Wraps the single top level tag in `input`.
+    pub fn new(parser: &'a Parser<'k>, input: &'a [u8]) -> Result<DmapRef<'a, 'k>, Error> {
+        let item = DmapRef::at(parser, input, 0)?;
+        if item.body.len() + 8 != input.len() {
+            return Err(Error::new(ErrorKind::TrailingData).at_offset(item.body.len() + 8));
+        }
+        Ok(item)
+    }
+
+    fn at(parser: &'a Parser<'k>, input: &'a [u8], offset: usize) -> Result<DmapRef<'a, 'k>, Error> {
+        let (code, body) = read_header(input, offset)?;
+        Ok(DmapRef {
+            parser,
+            code,
+            typedesc: parser.lookup_code(code),
+            body,
+            offset,
+        })
+    }
+
+    pub fn code(&self) -> Code {
+        Code(self.code)
+    }
+
+    /// The content name, if the code is in the dictionary.
+    pub fn name(&self) -> Option<&'k str> {
+        self.typedesc.map(|c| c.name)
+    }
+
+    /// The type, if the code is in the dictionary.
+    pub fn kind(&self) -> Option<TypeKind> {
+        self.typedesc.map(|c| c.kind)
+    }
+
+    pub fn is_container(&self) -> bool {
+        self.kind() == Some(TypeKind::Container)
+    }
+
+    /// The raw body of the tag.
+    pub fn body(&self) -> &'a [u8] {
+        self.body
+    }
+
+    /// Offset of the tag in the input.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Iterates over the children of a container. Leaves have no children.
+    pub fn children(&self) -> Children<'a, 'k> {
+        Children {
+            parser: self.parser,
+            tail: if self.is_container() { self.body } else { &[] },
+            offset: self.offset + 8,
+        }
+    }
+
+    /// The first child with the given content name.
+    pub fn get(&self, name: &str) -> Result<Option<DmapRef<'a, 'k>>, Error> {
+        for child in self.children() {
+            let child = child?;
+            if child.name() == Some(name) {
+                return Ok(Some(child));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Decodes the whole tag (including all children).
+    pub fn item(&self) -> Result<DmapItem<'a, 'k>, Error> {
+        self.item_within(0, &mut 0)
+    }
+
+    /// Decodes the value (including all children).
+    pub fn value(&self) -> Result<DmapValue<'a, 'k>, Error> {
+        self.value_within(0, &mut 0)
+    }
+
+    // the limits apply to the subtree, as if it was decoded on its own
+    fn item_within(&self, depth: usize, items: &mut usize) -> Result<DmapItem<'a, 'k>, Error> {
+        let name = match self.typedesc {
+            Some(c) => ItemName::Name(c.name),
+            None => ItemName::Code(self.code),
+        };
+        Ok(DmapItem { name, value: self.value_within(depth, items)? })
+    }
+
+    fn value_within(&self, depth: usize, items: &mut usize) -> Result<DmapValue<'a, 'k>, Error> {
+        let options = self.parser.decode_options();
+        *items += 1;
+        options.limits.check(self.kind(), self.body.len(), depth, *items)
+            .map_err(|e| e.at(self.offset, self.code))?;
+        let value = match self.kind() {
+            Some(TypeKind::Container) => {
+                let within = |e: Error| e.within(self.name().unwrap());
+                let children = self.children()
+                    .map(|c| c.and_then(|c| c.item_within(depth + 1, items)))
+                    .collect::<Result<_, _>>();
+                return children.map(DmapValue::Container).map_err(within);
+            }
+            kind => decode_leaf(kind, self.body, options),
+        };
+        value.map_err(|e| e.at(self.offset, self.code))
+    }
+}
+
+fn decode_leaf<'a, 'k>(kind: Option<TypeKind>, body: &'a [u8], options: DecodeOptions) -> Result<DmapValue<'a, 'k>, Error> {
+    let kind = match kind {
+        Some(k) => k,
+        None => return Ok(DmapValue::Unknown(body)),
+    };
+    let int = || read_int(kind, body, options.lengths);
+    Ok(match kind {
+        TypeKind::I8 => DmapValue::I8(int()? as i8),
+        TypeKind::U8 => DmapValue::U8(int()? as u8),
+        TypeKind::I16 => DmapValue::I16(int()? as i16),
+        TypeKind::U16 => DmapValue::U16(int()? as u16),
+        TypeKind::I32 => DmapValue::I32(int()? as i32),
+        TypeKind::U32 => DmapValue::U32(int()? as u32),
+        TypeKind::I64 => DmapValue::I64(int()? as i64),
+        TypeKind::U64 => DmapValue::U64(int()?),
+        TypeKind::Timestamp => DmapValue::Timestamp(int()? as u32),
+        TypeKind::Version => DmapValue::Version((int()? as u32).into()),
+        TypeKind::String => match read_str(body, options.strings)? {
+            Some(s) => DmapValue::String(s),
+            None => DmapValue::InvalidString(body),
+        },
+        TypeKind::Container => unreachable!(),
+    })
+}
+
+/// Iterator over the children of a `DmapRef`, see `DmapRef::children`.
+pub struct Children<'a, 'k: 'a> {
+    parser: &'a Parser<'k>,
+    tail: &'a [u8],
+    offset: usize,
+}
+
+impl<'a, 'k: 'a> Children<'a, 'k> {
+    /// All top level tags of `input`.
+    pub(crate) fn top_level(parser: &'a Parser<'k>, input: &'a [u8]) -> Children<'a, 'k> {
+        Children { parser, tail: input, offset: 0 }
+    }
+}
+
+impl<'a, 'k: 'a> Iterator for Children<'a, 'k> {
+    type Item = Result<DmapRef<'a, 'k>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.tail.is_empty() {
+            return None;
+        }
+        match DmapRef::at(self.parser, self.tail, self.offset) {
+            Ok(child) => {
+                self.tail = &self.tail[8 + child.body.len()..];
+                self.offset += 8 + child.body.len();
+                Some(Ok(child))
+            }
+            Err(e) => {
+                // stop after the first error
+                self.tail = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
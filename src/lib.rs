@@ -1,42 +1,185 @@
 extern crate byteorder;
+#[cfg(feature = "chrono")] extern crate chrono;
+#[cfg(feature = "time")] extern crate time;
+#[cfg(feature = "json")] extern crate serde_json;
+#[cfg(feature = "log")] extern crate log;
+#[cfg(feature = "tracing")] extern crate tracing;
+#[cfg(feature = "derive")] extern crate dmap_derive;
 #[macro_use] extern crate serde;
 #[macro_use] extern crate serde_derive;
 
+// for `dmap_enum!`, so users don't need serde as a direct dependency
+#[doc(hidden)]
+pub mod __serde {
+    pub use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
+}
+
 use byteorder::{BigEndian, ByteOrder};
 
-use std::{str, fmt};
+use std::{str, fmt, mem};
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 
-#[macro_use] mod enum_number;
+#[macro_use] mod dmap_enum;
+#[macro_use] mod trace;
 
+mod error;
 pub mod de;
 pub mod ser;
 pub mod value;
+pub mod owned;
+pub mod lazy;
+pub mod dump;
+#[cfg(feature = "json")] pub mod json;
+pub mod read;
+pub mod reader;
+pub mod write;
+pub mod writer;
+pub mod serde_helpers;
+pub mod typed;
 
-pub use value::{DmapValue, DmapItem};
+pub use error::{Error, ErrorKind};
+pub use value::{DmapValue, DmapItem, Version};
+pub use owned::{OwnedDmapItem, OwnedDmapValue};
+pub use lazy::DmapRef;
 pub use de::{from_slice, MapDeserializer};
+pub use read::from_reader;
 pub use ser::{to_vec, Serializer};
+pub use write::to_writer;
+#[cfg(feature = "derive")] pub use dmap_derive::{DmapEncode, DmapDecode};
 
-#[repr(u16)]
-enum_number!(TypeKind {
-    I8 = 1,
-    U8 = 2,
-    I16 = 3,
-    U16 = 4,
-    I32 = 5,
-    U32 = 6,
-    I64 = 7,
-    U64 = 8,
-    String = 9,
-    Timestamp = 10,
-    Version = 11,
-    Container = 12,
-});
+dmap_enum! {
+    @i16
+    #[repr(u16)]
+    pub enum TypeKind {
+        I8 = 1,
+        U8 = 2,
+        I16 = 3,
+        U16 = 4,
+        I32 = 5,
+        U32 = 6,
+        I64 = 7,
+        U64 = 8,
+        String = 9,
+        Timestamp = 10,
+        Version = 11,
+        Container = 12,
+    }
+}
 
+impl TypeKind {
+    /// Body length of fixed width types.
+    pub(crate) fn width(&self) -> Option<usize> {
+        match *self {
+            TypeKind::I8 | TypeKind::U8 => Some(1),
+            TypeKind::I16 | TypeKind::U16 => Some(2),
+            TypeKind::I32 | TypeKind::U32 | TypeKind::Timestamp | TypeKind::Version => Some(4),
+            TypeKind::I64 | TypeKind::U64 => Some(8),
+            TypeKind::String | TypeKind::Container => None,
+        }
+    }
 
+    fn as_str(&self) -> &'static str {
+        match *self {
+            TypeKind::I8 => "i8",
+            TypeKind::U8 => "u8",
+            TypeKind::I16 => "i16",
+            TypeKind::U16 => "u16",
+            TypeKind::I32 => "i32",
+            TypeKind::U32 => "u32",
+            TypeKind::I64 => "i64",
+            TypeKind::U64 => "u64",
+            TypeKind::String => "string",
+            TypeKind::Timestamp => "timestamp",
+            TypeKind::Version => "version",
+            TypeKind::Container => "container",
+        }
+    }
+}
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct ContentCode<'a> {
+impl fmt::Display for TypeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl str::FromStr for TypeKind {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<TypeKind, ParseError> {
+        use TypeKind::*;
+        [I8, U8, I16, U16, I32, U32, I64, U64, String, Timestamp, Version, Container].iter()
+            .find(|k| k.as_str() == s).cloned().ok_or(ParseError("type"))
+    }
+}
+
+/// A 4-byte content code like `mstt`.
+///
+/// Printed as ASCII, with non-printable bytes escaped as `\xNN` (and parsed the same way).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Code(pub [u8; 4]);
+
+impl From<[u8; 4]> for Code {
+    fn from(code: [u8; 4]) -> Code {
+        Code(code)
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &b in &self.0 {
+            if b.is_ascii_graphic() && b != b'\\' {
+                write!(f, "{}", b as char)?;
+            } else {
+                write!(f, "\\x{:02x}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl str::FromStr for Code {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Code, ParseError> {
+        let err = ParseError("content code");
+        let mut code = [0; 4];
+        let mut len = 0;
+        let mut rest = s.as_bytes();
+        while let Some(&first) = rest.first() {
+            let b = if rest.starts_with(b"\\x") {
+                // from_str_radix alone would take "+f"
+                let hex = rest.get(2..4).filter(|h| h.iter().all(u8::is_ascii_hexdigit)).ok_or(err)?;
+                rest = &rest[4..];
+                u8::from_str_radix(str::from_utf8(hex).unwrap(), 16).unwrap()
+            } else if first.is_ascii_graphic() && first != b'\\' {
+                rest = &rest[1..];
+                first
+            } else {
+                return Err(err);
+            };
+            *code.get_mut(len).ok_or(err)? = b;
+            len += 1;
+        }
+        if len == 4 { Ok(Code(code)) } else { Err(err) }
+    }
+}
+
+/// Failed to parse a `Code` or a `TypeKind` from a string.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseError(&'static str);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// An entry of the content code dictionary: maps a 4-byte code to a name and a type.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ContentCode<'a> {
     #[serde(rename = "dmap.contentcodesnumber", deserialize_with = "de_content_code", serialize_with = "ser_content_code")]
     code: [u8; 4],
     #[serde(borrow)]
@@ -46,6 +189,27 @@ struct ContentCode<'a> {
     kind: TypeKind,
 }
 
+impl<'a> ContentCode<'a> {
+    pub fn code(&self) -> Code {
+        Code(self.code)
+    }
+
+    /// The content name, like `dmap.status`.
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub fn kind(&self) -> TypeKind {
+        self.kind
+    }
+}
+
+impl<'a> fmt::Display for ContentCode<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.code(), self.name, self.kind)
+    }
+}
+
 fn de_content_code<'de, D>(d: D) -> Result<[u8; 4], D::Error>
     where D: serde::de::Deserializer<'de>
 {
@@ -94,9 +258,197 @@ struct ContentCodesResponseWrapper<'a> {
 
 pub struct Parser<'names> {
     types: Cow<'names, [ContentCode<'names>]>,
+    by_code: HashMap<[u8; 4], usize>,
+    by_name: HashMap<&'names str, usize>,
+    options: DecodeOptions,
 }
 
-static BOOTSTRAP_TYPES: &'static [ContentCode<'static>] = &[
+/// Knobs for decoding, see `Parser::set_decode_options`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DecodeOptions {
+    pub lengths: LengthMode,
+    pub strings: StringPolicy,
+    pub limits: Limits,
+}
+
+/// What to do with string tags that are not valid UTF-8.
+///
+/// Old Windows iTunes and Firefly servers send Latin-1 track names.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StringPolicy {
+    /// Fail with `ErrorKind::InvalidUtf8`.
+    #[default]
+    Strict,
+    /// Replace invalid sequences with U+FFFD.
+    Lossy,
+    /// Keep the bytes, `DmapValue` makes them a `DmapValue::InvalidString`.
+    /// Other types are handed the bytes instead of a string.
+    Raw,
+}
+
+/// Decodes the body of a string tag. `None` means the body should be kept
+/// as raw bytes.
+pub(crate) fn read_str(body: &[u8], strings: StringPolicy) -> Result<Option<Cow<'_, str>>, Error> {
+    match str::from_utf8(body) {
+        Ok(s) => Ok(Some(Cow::Borrowed(s))),
+        Err(_) => match strings {
+            StringPolicy::Strict => Err(Error::new(ErrorKind::InvalidUtf8)),
+            StringPolicy::Lossy => Ok(Some(String::from_utf8_lossy(body))),
+            StringPolicy::Raw => Ok(None),
+        },
+    }
+}
+
+/// Limits on the shape of the input, `None` means unlimited (the default).
+///
+/// Exceeding one fails with `ErrorKind::LimitExceeded`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// How deep containers may be nested, a top level container has depth 1.
+    pub max_depth: Option<usize>,
+    /// Total number of tags, containers included.
+    pub max_items: Option<usize>,
+    /// Longest string body in bytes.
+    pub max_string_len: Option<usize>,
+    /// Longest container body in bytes.
+    pub max_container_len: Option<usize>,
+    /// Longest body in bytes of any other tag: integers, versions and codes
+    /// missing from the dictionary.
+    pub max_body_len: Option<usize>,
+}
+
+impl Limits {
+    /// Limits for data sent by clients (DACP commands, `/update` bodies and
+    /// the like). Too tight for library listings.
+    pub fn untrusted() -> Limits {
+        Limits {
+            max_depth: Some(16),
+            max_items: Some(100_000),
+            max_string_len: Some(64 * 1024),
+            max_container_len: Some(16 * 1024 * 1024),
+            max_body_len: Some(64 * 1024),
+        }
+    }
+
+    // `depth` is the number of containers around the tag, `items` the number
+    // of tags seen so far including this one
+    pub(crate) fn check(&self, kind: Option<TypeKind>, len: usize, depth: usize, items: usize) -> Result<(), Error> {
+        let exceeded = |max: Option<usize>, n: usize| max.is_some_and(|max| n > max);
+        self.check_items(items)?;
+        let limit = match kind {
+            Some(TypeKind::Container) if exceeded(self.max_depth, depth + 1) => Limit::Depth,
+            Some(TypeKind::Container) if exceeded(self.max_container_len, len) => Limit::ContainerLength,
+            Some(TypeKind::String) if exceeded(self.max_string_len, len) => Limit::StringLength,
+            Some(TypeKind::Container) | Some(TypeKind::String) => return Ok(()),
+            _ if exceeded(self.max_body_len, len) => Limit::BodyLength,
+            _ => return Ok(()),
+        };
+        Err(Error::new(ErrorKind::LimitExceeded(limit)))
+    }
+
+    // only `max_items`, for a tag whose kind isn't known yet
+    pub(crate) fn check_items(&self, items: usize) -> Result<(), Error> {
+        if self.max_items.is_some_and(|max| items > max) {
+            return Err(Error::new(ErrorKind::LimitExceeded(Limit::Items)));
+        }
+        Ok(())
+    }
+}
+
+/// The limit that was exceeded, see `ErrorKind::LimitExceeded`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Depth,
+    Items,
+    StringLength,
+    ContainerLength,
+    BodyLength,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Limit::Depth => "nesting depth",
+            Limit::Items => "number of items",
+            Limit::StringLength => "string length",
+            Limit::ContainerLength => "container length",
+            Limit::BodyLength => "body length",
+        })
+    }
+}
+
+/// What to do with integer tags whose length doesn't match their type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LengthMode {
+    /// Fail with `ErrorKind::InvalidLength`.
+    #[default]
+    Strict,
+    /// Accept anything from 1 to 8 bytes as long as the value fits (some
+    /// servers send 1-byte booleans under u32 codes).
+    Lenient,
+}
+
+/// Reads the body of an integer tag (including timestamps and versions).
+///
+/// Returns the value's bit pattern in the low bits, so the caller just has to
+/// cast it to the right type.
+pub(crate) fn read_int(kind: TypeKind, body: &[u8], lengths: LengthMode) -> Result<u64, Error> {
+    let width = kind.width().expect("not an integer type");
+    let signed = matches!(kind, TypeKind::I8 | TypeKind::I16 | TypeKind::I32 | TypeKind::I64);
+    let invalid = || Error::new(ErrorKind::InvalidLength { expected: width, found: body.len() });
+    if body.len() != width && (lengths == LengthMode::Strict || body.is_empty() || body.len() > 8) {
+        return Err(invalid());
+    }
+
+    let raw = BigEndian::read_uint(body, body.len());
+    if body.len() == width {
+        return Ok(raw);
+    }
+    // lenient: widen or narrow, but only if the value survives
+    let (shift, bits) = (64 - 8 * body.len() as u32, 8 * width as u32);
+    let value = if signed { (((raw << shift) as i64) >> shift) as u64 } else { raw };
+    let fits = bits == 64 || if signed {
+        let v = value as i64;
+        v >= -(1 << (bits - 1)) && v < 1 << (bits - 1)
+    } else {
+        value < 1 << bits
+    };
+    if fits { Ok(value & (u64::MAX >> (64 - bits))) } else { Err(invalid()) }
+}
+
+/// What `Parser::merge` does with codes that are already known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Keep the existing entry, ignore the new one.
+    KeepExisting,
+    /// Replace the existing entry with the new one.
+    Override,
+}
+
+/// Reasons why a `/content-codes` reply could not be turned into a `Parser`.
+#[derive(Debug)]
+pub enum ContentCodesError {
+    /// The reply could not be decoded as a content codes response.
+    Malformed(Error),
+    /// The server answered with a `dmap.status` other than 200.
+    Status(i32),
+    /// One of the codes we need to fix up was not in the dictionary.
+    MissingFixup(&'static str),
+}
+
+impl fmt::Display for ContentCodesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ContentCodesError::Malformed(ref e) => write!(f, "malformed content codes: {}", e),
+            ContentCodesError::Status(s) => write!(f, "content codes request failed with status {}", s),
+            ContentCodesError::MissingFixup(n) => write!(f, "content code {} is missing", n),
+        }
+    }
+}
+
+impl std::error::Error for ContentCodesError {}
+
+static BOOTSTRAP_TYPES: &[ContentCode<'static>] = &[
     ContentCode { code: *b"mccr", name: "dmap.contentcodesresponse", kind: TypeKind::Container },
     ContentCode { code: *b"mstt", name: "dmap.status", kind: TypeKind::I32 },
     ContentCode { code: *b"mdcl", name: "dmap.dictionary", kind: TypeKind::Container },
@@ -105,74 +457,238 @@ static BOOTSTRAP_TYPES: &'static [ContentCode<'static>] = &[
     ContentCode { code: *b"mcty", name: "dmap.contentcodestype", kind: TypeKind::I16 },
 ];
 
+// generated by build.rs from data/content-codes.txt
+include!(concat!(env!("OUT_DIR"), "/builtin_types.rs"));
+
+// apple gave these a wrong type (???wtf???)
+static TYPE_FIXUPS: &[(&str, TypeKind)] = &[
+    ("dmap.editcommandssupported", TypeKind::I16),
+    ("dmap.authenticationschemes", TypeKind::I8),
+    ("com.apple.itunes.itms-playlistid", TypeKind::I64),
+    ("com.apple.itunes.rental-pb-start", TypeKind::String),
+    ("dmap.itemdateplayed", TypeKind::I32),
+];
+
 impl<'names> Parser<'names> {
+    /// Builds a parser from a `/content-codes` reply.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the reply is not usable, see `try_new` for a fallible version.
     pub fn new(content_codes: &'names [u8]) -> Parser<'names> {
+        match Parser::try_new(content_codes) {
+            Ok(p) => p,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Builds a parser from a `/content-codes` reply.
+    pub fn try_new(content_codes: &'names [u8]) -> Result<Parser<'names>, ContentCodesError> {
+        // the reply might come from anywhere
+        let mut bootstrap = Parser::bootstrap();
+        bootstrap.set_decode_options(DecodeOptions { limits: Limits::untrusted(), ..DecodeOptions::default() });
+        let ccsw: ContentCodesResponseWrapper = de::from_slice(&bootstrap, content_codes)
+            .map_err(ContentCodesError::Malformed)?;
+
+        let ccs = ccsw.inner;
+        if ccs.status != 200 {
+            return Err(ContentCodesError::Status(ccs.status));
+        }
+
+        let mut parser = Parser::from_types(Cow::Owned(ccs.dictionary));
+        for &(name, kind) in TYPE_FIXUPS {
+            parser.set_kind(name, kind).ok_or(ContentCodesError::MissingFixup(name))?;
+        }
+
+        Ok(parser)
+    }
+
+    /// A parser using the compiled-in list of well-known dmap, daap, dacp and dpap codes.
+    ///
+    /// Useful for servers that don't serve `/content-codes` or serve incomplete ones.
+    pub fn builtin() -> Parser<'names> {
+        Parser::from_types(Cow::Borrowed(BUILTIN_TYPES))
+    }
+
+    /// A parser that only knows the codes needed to read a `/content-codes` reply.
+    pub fn bootstrap() -> Parser<'names> {
+        Parser::from_types(Cow::Borrowed(BOOTSTRAP_TYPES))
+    }
+
+    /// A parser without any codes, for `Writer::new` style data and the
+    /// derived decoders in `typed`.
+    pub fn empty() -> Parser<'names> {
+        Parser::from_types(Cow::Borrowed(&[]))
+    }
+
+    fn from_types(types: Cow<'names, [ContentCode<'names>]>) -> Parser<'names> {
         let mut parser = Parser {
-            types: Cow::Borrowed(BOOTSTRAP_TYPES),
+            types,
+            by_code: HashMap::new(),
+            by_name: HashMap::new(),
+            options: DecodeOptions::default(),
         };
+        parser.reindex();
+        parser
+    }
 
-        let ccsw: ContentCodesResponseWrapper = de::from_slice(&parser, content_codes).unwrap();
+    // if a server lists a code twice the first entry wins
+    fn reindex(&mut self) {
+        self.by_code.clear();
+        self.by_name.clear();
+        for (i, c) in self.types.iter().enumerate() {
+            self.by_code.entry(c.code).or_insert(i);
+            self.by_name.entry(c.name).or_insert(i);
+        }
+    }
 
-        let mut ccs = ccsw.inner;
-        assert_eq!(ccs.status, 200);
+    pub fn decode_options(&self) -> DecodeOptions {
+        self.options
+    }
 
-        //println!("{:#?}", ccs);
+    pub fn set_decode_options(&mut self, options: DecodeOptions) {
+        self.options = options;
+    }
 
-        // fix these because apple gave them a wrong type (???wtf???)
-        // FIXME develop a better solution
-        ccs.dictionary.iter_mut().find(|x| x.name == "dmap.editcommandssupported").unwrap().kind = TypeKind::I16;
-        ccs.dictionary.iter_mut().find(|x| x.name == "dmap.authenticationschemes").unwrap().kind = TypeKind::I8;
-        ccs.dictionary.iter_mut().find(|x| x.name == "com.apple.itunes.itms-playlistid").unwrap().kind = TypeKind::I64;
-        ccs.dictionary.iter_mut().find(|x| x.name == "com.apple.itunes.rental-pb-start").unwrap().kind = TypeKind::String;
-        ccs.dictionary.iter_mut().find(|x| x.name == "dmap.itemdateplayed").unwrap().kind = TypeKind::I32;
-        parser.types = Cow::Owned(ccs.dictionary);
+    /// All content codes this parser knows, in the order they were advertised.
+    pub fn content_codes(&self) -> impl Iterator<Item = &ContentCode<'names>> {
+        self.types.iter()
+    }
 
-        parser
+    pub fn lookup_code<C: Into<Code>>(&self, code: C) -> Option<&ContentCode<'names>> {
+        self.by_code.get(&code.into().0).map(|&i| &self.types[i])
+    }
+
+    pub fn lookup_name(&self, name: &str) -> Option<&ContentCode<'names>> {
+        self.by_name.get(name).map(|&i| &self.types[i])
+    }
+
+    /// Adds a content code.
+    ///
+    /// Existing entries with the same code or the same name are replaced.
+    pub fn insert<C: Into<Code>>(&mut self, code: C, name: &'names str, kind: TypeKind) {
+        let code = code.into().0;
+        {
+            let types = self.types.to_mut();
+            types.retain(|x| x.code != code && x.name != name);
+            types.push(ContentCode { code, name, kind });
+        }
+        self.reindex();
+    }
+
+    /// Changes the type of the content code called `name`.
+    ///
+    /// Returns the previous type, or `None` if there is no such code.
+    pub fn set_kind(&mut self, name: &str, kind: TypeKind) -> Option<TypeKind> {
+        let pos = *self.by_name.get(name)?;
+        let entry = &mut self.types.to_mut()[pos];
+        Some(mem::replace(&mut entry.kind, kind))
+    }
+
+    /// Removes a content code, returns whether it was present.
+    pub fn remove<C: Into<Code>>(&mut self, code: C) -> bool {
+        let code = code.into().0;
+        if !self.by_code.contains_key(&code) {
+            return false;
+        }
+        self.types.to_mut().retain(|x| x.code != code);
+        self.reindex();
+        true
+    }
+
+    /// Adds all content codes of `other` to this parser.
+    ///
+    /// `policy` decides what happens when both parsers have an entry with the
+    /// same code or the same name.
+    pub fn merge(&mut self, other: &Parser<'names>, policy: MergePolicy) {
+        // same result as calling `insert` for every entry, but with a single reindex
+        let mut added = Vec::new();
+        match policy {
+            MergePolicy::KeepExisting => {
+                let mut codes: HashSet<[u8; 4]> = self.by_code.keys().cloned().collect();
+                let mut names: HashSet<&str> = self.by_name.keys().cloned().collect();
+                for c in other.types.iter() {
+                    if !codes.contains(&c.code) && !names.contains(c.name) {
+                        codes.insert(c.code);
+                        names.insert(c.name);
+                        added.push(c.clone());
+                    }
+                }
+            }
+            MergePolicy::Override => {
+                // an entry survives unless a later one has the same code or name
+                let (mut codes, mut names) = (HashSet::new(), HashSet::new());
+                for c in other.types.iter().rev() {
+                    if codes.insert(c.code) & names.insert(c.name) {
+                        added.push(c.clone());
+                    }
+                }
+                added.reverse();
+                self.types.to_mut().retain(|x| !codes.contains(&x.code) && !names.contains(x.name));
+            }
+        }
+        self.types.to_mut().extend(added);
+        self.reindex();
     }
 
     #[cfg(test)]
-    fn old_parse<'a>(&self, data: &'a [u8]) -> DmapItem<'a, 'names> {
-        let (x, t) = self.old_do_parse(data);
+    fn old_parse<'a>(&self, data: &'a [u8]) -> Result<DmapItem<'a, 'names>, Error> {
+        let (x, t) = self.old_do_parse(data, 0, &mut 0)?;
         assert!(t.is_empty());
-        x
+        Ok(x)
     }
 
+    // The original tree parser, only kept as a reference for the tests. It
+    // applies the same checks as the real decoders but doesn't report offsets.
     #[cfg(test)]
-    fn old_do_parse<'a>(&self, data: &'a [u8]) -> (DmapItem<'a, 'names>, &'a [u8]) {
+    fn old_do_parse<'a>(&self, data: &'a [u8], depth: usize, items: &mut usize)
+        -> Result<(DmapItem<'a, 'names>, &'a [u8]), Error>
+    {
         use value::ItemName;
 
+        let truncated = || Error::new(ErrorKind::Truncated);
         let mut code = [0; 4];
-        code.copy_from_slice(&data[0..4]);
-        let size = BigEndian::read_u32(&data[4..8]) as usize;
+        code.copy_from_slice(data.get(0..4).ok_or_else(truncated)?);
+        let size = BigEndian::read_u32(data.get(4..8).ok_or_else(truncated)?) as usize;
+        let body = data.get(8..).and_then(|d| d.get(..size)).ok_or_else(truncated)?;
+        let tail = &data[8 + size..];
 
-        let body = &data[8..8+size];
-        let tail = &data[8+size..];
+        let typedesc = self.lookup_code(code);
+        *items += 1;
+        self.options.limits.check(typedesc.map(|c| c.kind), size, depth, *items)?;
 
-        let item = match self.types.iter().find(|x| x.code == code) {
+        let item = match typedesc {
             Some(typ) => DmapItem {
                 name: ItemName::Name(typ.name),
-                value: match typ.kind {
-                    TypeKind::I8 => DmapValue::I8(body[0] as i8),
-                    TypeKind::U8 => DmapValue::U8(body[0]),
-                    TypeKind::I16 => DmapValue::I16(BigEndian::read_i16(body)),
-                    TypeKind::U16 => DmapValue::U16(BigEndian::read_u16(body)),
-                    TypeKind::I32 => DmapValue::I32(BigEndian::read_i32(body)),
-                    TypeKind::U32 | TypeKind::Timestamp | TypeKind::Version /*fixme*/
-                        => DmapValue::U32(BigEndian::read_u32(body)),
-                    TypeKind::I64 => DmapValue::I64(BigEndian::read_i64(body)),
-                    TypeKind::U64 => DmapValue::U64(BigEndian::read_u64(body)),
-                    TypeKind::String => DmapValue::String(str::from_utf8(body).unwrap()), // fixme unwrap
-                    TypeKind::Container => {
-                        let mut values = Vec::new();
-                        let mut todo = body;
-                        while !todo.is_empty() {
-                            let (v, t) = self.old_do_parse(todo);
-                            values.push(v);
-                            todo = t;
+                value: {
+                    let int = || read_int(typ.kind, body, self.options.lengths);
+                    match typ.kind {
+                        TypeKind::I8 => DmapValue::I8(int()? as i8),
+                        TypeKind::U8 => DmapValue::U8(int()? as u8),
+                        TypeKind::I16 => DmapValue::I16(int()? as i16),
+                        TypeKind::U16 => DmapValue::U16(int()? as u16),
+                        TypeKind::I32 => DmapValue::I32(int()? as i32),
+                        TypeKind::U32 => DmapValue::U32(int()? as u32),
+                        TypeKind::Timestamp => DmapValue::Timestamp(int()? as u32),
+                        TypeKind::Version => DmapValue::Version((int()? as u32).into()),
+                        TypeKind::I64 => DmapValue::I64(int()? as i64),
+                        TypeKind::U64 => DmapValue::U64(int()?),
+                        TypeKind::String => match read_str(body, self.options.strings)? {
+                            Some(s) => DmapValue::String(s),
+                            None => DmapValue::InvalidString(body),
+                        },
+                        TypeKind::Container => {
+                            let mut values = Vec::new();
+                            let mut todo = body;
+                            while !todo.is_empty() {
+                                let (v, t) = self.old_do_parse(todo, depth + 1, items)?;
+                                values.push(v);
+                                todo = t;
+                            }
+                            DmapValue::Container(values)
                         }
-                        DmapValue::Container(values)
                     }
-                }
+                },
             },
             None => DmapItem {
                 name: ItemName::Code(code),
@@ -180,7 +696,7 @@ impl<'names> Parser<'names> {
             },
         };
 
-        (item, tail)
+        Ok((item, tail))
     }
 }
 
@@ -191,12 +707,12 @@ mod tests {
 
     fn verify_parse<'a, 'b, 'k: 'b>(parser: &'a Parser<'k>, data: &'b [u8]) -> DmapItem<'b, 'b> {
         let val1: DmapItem = de::from_slice(parser, data).unwrap();
-        let val2 = parser.old_parse(data);
+        let val2 = parser.old_parse(data).unwrap();
 
-        let data2 = ser::to_vec(&parser, &val1).unwrap();
+        let data2 = ser::to_vec(parser, &val1).unwrap();
         assert_eq!(data.len(), data2.len());
         assert_eq!(data, data2.as_slice());
-        let val3: DmapItem = de::from_slice(&parser, data2.as_slice()).unwrap();
+        let val3: DmapItem = de::from_slice(parser, data2.as_slice()).unwrap();
         assert_eq!(val1, val3);
 
 
@@ -212,6 +728,774 @@ mod tests {
         verify_parse(&parser, ccs);
     }
 
+    #[test]
+    fn content_codes_errors() {
+        let ccs = include_bytes!("../testdata/content-codes.bin");
+        match Parser::try_new(&ccs[..ccs.len() - 1]) {
+            Err(ContentCodesError::Malformed(_)) => (),
+            _ => panic!("truncated content codes accepted"),
+        }
+
+        let bootstrap = Parser::bootstrap();
+        let mut response = ContentCodesResponseWrapper {
+            inner: ContentCodesResponse { status: 500, dictionary: BOOTSTRAP_TYPES.to_vec() },
+        };
+        let data = ser::to_vec(&bootstrap, &response).unwrap();
+        match Parser::try_new(&data) {
+            Err(ContentCodesError::Status(500)) => (),
+            _ => panic!("non-200 status accepted"),
+        }
+
+        response.inner.status = 200;
+        let data = ser::to_vec(&bootstrap, &response).unwrap();
+        match Parser::try_new(&data) {
+            Err(ContentCodesError::MissingFixup("dmap.editcommandssupported")) => (),
+            _ => panic!("missing fixup accepted"),
+        }
+    }
+
+    #[test]
+    fn decode_errors() {
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let login = include_bytes!("../testdata/login.bin");
+
+        let err = de::from_slice::<DmapItem>(&parser, &login[..login.len() - 2]).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Truncated);
+        assert_eq!(err.offset(), Some(0));
+        assert_eq!(err.code(), Some(*b"mlog"));
+
+        let mut trailing = login.to_vec();
+        trailing.extend_from_slice(b"mstt");
+        let err = de::from_slice::<DmapItem>(&parser, &trailing).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::TrailingData);
+        assert_eq!(err.offset(), Some(login.len()));
+
+        // 'mlid' is the session id, which comes right after the status
+        let mut invalid = login.to_vec();
+        assert_eq!(&invalid[8..12], b"mstt");
+        invalid[20..24].copy_from_slice(b"minm");
+        invalid[28] = 0xff;
+        let err = de::from_slice::<DmapItem>(&parser, &invalid).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidUtf8);
+        assert_eq!(err.offset(), Some(20));
+        assert_eq!(err.code(), Some(*b"minm"));
+        assert_eq!(err.path(), ["dmap.loginresponse"]);
+    }
+
+    #[test]
+    fn lengths() {
+        let mut parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let encode = |status: &[u8], id: &[u8]| {
+            let mut writer = writer::Writer::new();
+            writer.begin_container(*b"mlog").unwrap()
+                .write_bytes(*b"mstt", status).unwrap()
+                .write_bytes(*b"mlid", id).unwrap()
+                .end_container().unwrap();
+            writer.finish().unwrap()
+        };
+        let short = encode(&[0xff], &[0, 0, 0, 0, 0, 0, 0, 7]);
+
+        let err = de::from_slice::<DmapItem>(&parser, &short).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidLength { expected: 4, found: 1 });
+        assert_eq!(err.offset(), Some(8));
+        assert_eq!(err.code(), Some(*b"mstt"));
+        let err = DmapRef::new(&parser, &short).unwrap().value().unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidLength { expected: 4, found: 1 });
+
+        parser.set_decode_options(DecodeOptions { lengths: LengthMode::Lenient, ..DecodeOptions::default() });
+        let login = de::from_slice::<DmapItem>(&parser, &short).unwrap();
+        assert_eq!(login.get("dmap.status").and_then(|i| i.value.as_i32()), Some(-1));
+        assert_eq!(login.get("dmap.sessionid").and_then(|i| i.value.as_i32()), Some(7));
+        let login = read::from_reader::<_, OwnedDmapItem>(&parser, &short[..]);
+        assert!(login.is_ok());
+        let login = DmapRef::new(&parser, &short).unwrap().item().unwrap();
+        assert_eq!(login.get("dmap.status").and_then(|i| i.value.as_i32()), Some(-1));
+
+        // lenient still refuses values that don't fit
+        let big = encode(&[1], &[0, 0, 0, 1, 0, 0, 0, 0]);
+        let err = de::from_slice::<DmapItem>(&parser, &big).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidLength { expected: 4, found: 8 });
+        assert_eq!(err.code(), Some(*b"mlid"));
+        let err = de::from_slice::<DmapItem>(&parser, &encode(&[], &[0; 4])).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::InvalidLength { expected: 4, found: 0 });
+    }
+
+    #[test]
+    fn strings() {
+        let mut parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let mut latin1 = include_bytes!("../testdata/login.bin").to_vec();
+        latin1[20..24].copy_from_slice(b"minm");
+        latin1[28..32].copy_from_slice(b"Bj\xf6r");
+        let name = |item: &DmapItem| item.get("dmap.itemname").unwrap().value.clone().into_owned();
+
+        assert_eq!(*parser.old_parse(&latin1).unwrap_err().kind(), ErrorKind::InvalidUtf8);
+
+        parser.set_decode_options(DecodeOptions { strings: StringPolicy::Lossy, ..DecodeOptions::default() });
+        let lossy = OwnedDmapValue::String("Bj\u{fffd}r".to_string());
+        assert_eq!(name(&de::from_slice(&parser, &latin1).unwrap()), lossy);
+        assert_eq!(name(&DmapRef::new(&parser, &latin1).unwrap().item().unwrap()), lossy);
+        let owned: OwnedDmapItem = read::from_reader(&parser, &latin1[..]).unwrap();
+        assert_eq!(name(&owned.as_borrowed()), lossy);
+        assert_eq!(name(&parser.old_parse(&latin1).unwrap()), lossy);
+
+        parser.set_decode_options(DecodeOptions { strings: StringPolicy::Raw, ..DecodeOptions::default() });
+        let raw = OwnedDmapValue::InvalidString(b"Bj\xf6r".to_vec());
+        let item: DmapItem = de::from_slice(&parser, &latin1).unwrap();
+        assert_eq!(name(&item), raw);
+        assert_eq!(name(&DmapRef::new(&parser, &latin1).unwrap().item().unwrap()), raw);
+        let owned: OwnedDmapItem = read::from_reader(&parser, &latin1[..]).unwrap();
+        assert_eq!(name(&owned.as_borrowed()), raw);
+        assert_eq!(name(&parser.old_parse(&latin1).unwrap()), raw);
+        assert_eq!(ser::to_vec(&parser, &item).unwrap(), latin1);
+        assert_eq!(item.get("dmap.status").unwrap().value, DmapValue::I32(200));
+    }
+
+    #[test]
+    fn limits() {
+        let mut parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let mut writer = writer::Writer::new();
+        for _ in 0..20 {
+            writer.begin_container(*b"mlcl").unwrap();
+        }
+        writer.write_bytes(*b"minm", &[b'x'; 100]).unwrap();
+        for _ in 0..20 {
+            writer.end_container().unwrap();
+        }
+        let nested = writer.finish().unwrap();
+        assert!(de::from_slice::<DmapItem>(&parser, &nested).is_ok());
+
+        let decode_all = |parser: &Parser| {
+            let errors = [
+                de::from_slice::<DmapItem>(parser, &nested).unwrap_err(),
+                read::from_reader::<_, OwnedDmapItem>(parser, &nested[..]).unwrap_err(),
+                DmapRef::new(parser, &nested).unwrap().value().unwrap_err(),
+            ];
+            for e in &errors[1..] {
+                assert_eq!(e, &errors[0]);
+            }
+            // the pull parser doesn't know about container names
+            let e = reader::Reader::new(parser, &nested).find_map(Result::err).unwrap();
+            assert_eq!((e.kind(), e.offset(), e.code()), (errors[0].kind(), errors[0].offset(), errors[0].code()));
+            assert_eq!(parser.old_parse(&nested).unwrap_err().kind(), errors[0].kind());
+            errors[0].clone()
+        };
+        let limited = |limits| DecodeOptions { limits, ..DecodeOptions::default() };
+
+        parser.set_decode_options(limited(Limits::untrusted()));
+        let err = decode_all(&parser);
+        assert_eq!(*err.kind(), ErrorKind::LimitExceeded(Limit::Depth));
+        assert_eq!(err.offset(), Some(16 * 8));
+        assert_eq!(err.path().len(), 16);
+
+        parser.set_decode_options(limited(Limits { max_items: Some(5), ..Limits::default() }));
+        let err = decode_all(&parser);
+        assert_eq!(*err.kind(), ErrorKind::LimitExceeded(Limit::Items));
+        assert_eq!(err.offset(), Some(5 * 8));
+
+        parser.set_decode_options(limited(Limits { max_string_len: Some(99), ..Limits::default() }));
+        let err = decode_all(&parser);
+        assert_eq!(*err.kind(), ErrorKind::LimitExceeded(Limit::StringLength));
+        assert_eq!(err.code(), Some(*b"minm"));
+        assert_eq!(err.to_string(), "string length limit exceeded in 'minm' at offset 160 (inside ".to_string()
+            + &["dmap.listing"; 20].join("/") + ")");
+
+        parser.set_decode_options(limited(Limits { max_container_len: Some(200), ..Limits::default() }));
+        let err = decode_all(&parser);
+        assert_eq!(*err.kind(), ErrorKind::LimitExceeded(Limit::ContainerLength));
+        assert_eq!(err.offset(), Some(0));
+
+        // an unknown code claiming a huge body must fail before it is buffered
+        let mut huge = b"xxxx\xff\xff\xff\xf0".to_vec();
+        huge.extend_from_slice(&[0; 1024]);
+        parser.set_decode_options(limited(Limits::untrusted()));
+        let err = read::from_reader::<_, OwnedDmapItem>(&parser, &huge[..]).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::LimitExceeded(Limit::BodyLength));
+        assert_eq!((err.offset(), err.code()), (Some(0), Some(*b"xxxx")));
+        parser.set_decode_options(limited(Limits { max_body_len: Some(2), ..Limits::default() }));
+        let err = read::from_reader::<_, OwnedDmapItem>(&parser, &b"mstt\0\0\0\x04\0\0\0\xc8"[..]).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::LimitExceeded(Limit::BodyLength));
+    }
+
+    #[test]
+    fn enums() {
+        dmap_enum! {
+            enum State {
+                Stopped = 2,
+                Playing = 4,
+                Huge = 300,
+            }
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        enum Shuffle { Off, On }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Status {
+            #[serde(rename = "dacp.playerstate")]
+            state: State,
+            #[serde(rename = "dmap.itemid")]
+            id: State,
+            #[serde(rename = "dacp.shufflestate")]
+            shuffle: Shuffle,
+        }
+
+        let parser = Parser::builtin();
+        let status = Status { state: State::Playing, id: State::Stopped, shuffle: Shuffle::On };
+        let data = ser::to_vec(&parser, &status).unwrap();
+        assert_eq!(data, b"caps\0\0\0\x01\x04miid\0\0\0\x04\0\0\0\x02cash\0\0\0\x01\x01");
+        let mut streamed = Vec::new();
+        to_writer(&parser, &mut streamed, &status).unwrap();
+        assert_eq!(streamed, data);
+        assert_eq!(de::from_slice::<Status>(&parser, &data).unwrap(), status);
+        assert_eq!(from_reader::<_, Status>(&parser, &data[..]).unwrap(), status);
+
+        // 300 doesn't fit in caps' u8
+        let err = ser::to_vec(&parser, &Status { state: State::Huge, ..status }).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::WrongKind(TypeKind::U8));
+
+        let mut unknown = data.clone();
+        unknown[8] = 3;
+        let err = de::from_slice::<Status>(&parser, &unknown).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Message("unknown State value: 3".to_string()));
+        assert_eq!((err.offset(), err.code()), (Some(0), Some(*b"caps")));
+        let mut unknown = data.clone();
+        unknown[29] = 2;
+        let err = from_reader::<_, Status>(&parser, &unknown[..]).unwrap_err();
+        assert_eq!((err.offset(), err.code()), (Some(21), Some(*b"cash")));
+    }
+
+    #[test]
+    fn encode_errors() {
+        #[derive(Serialize)]
+        struct Flag {
+            #[serde(rename = "dmap.status")]
+            status: bool,
+        }
+
+        #[derive(Serialize)]
+        struct Bogus {
+            #[serde(rename = "dmap.nosuchthing")]
+            status: i32,
+        }
+
+        let parser = Parser::bootstrap();
+        let err = ser::to_vec(&parser, &Flag { status: true }).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Unsupported("bool"));
+        let err = ser::to_vec(&parser, &Bogus { status: 200 }).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::UnknownName("dmap.nosuchthing".to_string()));
+        let err = ser::to_vec(&parser, &vec![1, 2, 3]).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Unsupported("sequence outside of a map"));
+
+        // a tag needs a code, so a bare value can't be one
+        for err in [
+            ser::to_vec(&parser, &5i32).unwrap_err(), to_writer(&parser, Vec::new(), &5i32).unwrap_err(),
+            ser::to_vec(&parser, "abc").unwrap_err(), to_writer(&parser, Vec::new(), "abc").unwrap_err(),
+        ] {
+            assert_eq!(*err.kind(), ErrorKind::Unsupported("value outside of a map"));
+        }
+    }
+
+    #[test]
+    fn builtin() {
+        let parser = Parser::builtin();
+        verify_parse(&parser, include_bytes!("../testdata/content-codes.bin"));
+        verify_parse(&parser, include_bytes!("../testdata/server-info.bin"));
+        verify_parse(&parser, include_bytes!("../testdata/login.bin"));
+
+        // the builtin codes must agree with what iTunes tells us (after our fixups)
+        let itunes = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        for c in itunes.types.iter() {
+            if let Some(b) = parser.types.iter().find(|b| b.name == c.name) {
+                assert_eq!((b.code, b.kind), (c.code, c.kind), "{}", c.name);
+            }
+        }
+        assert!(parser.types.iter().any(|c| c.name == "dacp.playerstate"));
+        assert!(parser.types.iter().any(|c| c.name == "dpap.imagefilename"));
+    }
+
+    #[test]
+    fn registry() {
+        let mut parser = Parser::bootstrap();
+        assert_eq!(parser.set_kind("dmap.status", TypeKind::U32), Some(TypeKind::I32));
+        assert_eq!(parser.set_kind("dmap.nosuchthing", TypeKind::U32), None);
+
+        parser.insert(*b"mlog", "dmap.loginresponse", TypeKind::Container);
+        parser.insert(*b"mlid", "dmap.sessionid", TypeKind::I32);
+        let login = verify_parse(&parser, include_bytes!("../testdata/login.bin"));
+        match login.value {
+            DmapValue::Container(c) => assert_eq!(c[0].value, DmapValue::U32(200)),
+            _ => unreachable!(),
+        }
+
+        // same name, new code replaces the old entry
+        parser.insert(*b"xxxx", "dmap.sessionid", TypeKind::I32);
+        assert!(!parser.remove(*b"mlid"));
+        assert!(parser.remove(*b"xxxx"));
+
+        let mut merged = Parser::bootstrap();
+        merged.merge(&parser, MergePolicy::KeepExisting);
+        assert_eq!(merged.set_kind("dmap.status", TypeKind::I32), Some(TypeKind::I32));
+        assert!(merged.types.iter().any(|x| x.name == "dmap.loginresponse"));
+        merged.merge(&parser, MergePolicy::Override);
+        assert_eq!(merged.set_kind("dmap.status", TypeKind::I32), Some(TypeKind::U32));
+        assert_eq!(merged.types.len(), parser.types.len());
+
+        // merge must agree with inserting one entry at a time, also for
+        // entries that clash with each other
+        let mut other = Parser::bootstrap();
+        other.types.to_mut().extend_from_slice(&[
+            ContentCode { code: *b"aaaa", name: "x.one", kind: TypeKind::U8 },
+            ContentCode { code: *b"bbbb", name: "x.two", kind: TypeKind::U8 },
+            ContentCode { code: *b"aaaa", name: "x.three", kind: TypeKind::U16 },
+            ContentCode { code: *b"mstt", name: "x.two", kind: TypeKind::U32 },
+        ]);
+        other.reindex();
+        for &policy in &[MergePolicy::KeepExisting, MergePolicy::Override] {
+            let mut merged = Parser::from_types(parser.types.clone());
+            merged.merge(&other, policy);
+            let mut inserted = Parser::from_types(parser.types.clone());
+            for c in other.types.iter() {
+                let conflict = inserted.by_code.contains_key(&c.code) || inserted.by_name.contains_key(c.name);
+                if !conflict || policy == MergePolicy::Override {
+                    inserted.insert(c.code, c.name, c.kind);
+                }
+            }
+            assert_eq!(merged.types, inserted.types, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn code_strings() {
+        assert_eq!("mstt".parse(), Ok(Code(*b"mstt")));
+        assert_eq!("f\\x8dch".parse(), Ok(Code(*b"f\x8dch")));
+        assert!("mst".parse::<Code>().is_err());
+        assert!("mstt1".parse::<Code>().is_err());
+        assert!("m\\x8".parse::<Code>().is_err());
+        assert!("\\x+1ab".parse::<Code>().is_err());
+        assert_eq!(Code(*b"f\x8dch").to_string(), "f\\x8dch");
+        assert_eq!(Code(*b"a\\ b").to_string(), "a\\x5c\\x20b");
+
+        for c in Parser::builtin().content_codes() {
+            assert_eq!(c.kind().to_string().parse(), Ok(c.kind()));
+            assert_eq!(c.code().to_string().parse(), Ok(c.code()));
+        }
+        assert!("int".parse::<TypeKind>().is_err());
+
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let status = parser.lookup_name("dmap.status").unwrap();
+        assert_eq!(parser.lookup_code(*b"mstt"), Some(status));
+        assert_eq!(parser.lookup_code(status.code()), Some(status));
+        assert_eq!(status.to_string(), "mstt dmap.status i32");
+    }
+
+    #[test]
+    fn typed_values() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let info = verify_parse(&parser, include_bytes!("../testdata/server-info.bin"));
+        match info.value {
+            DmapValue::Container(c) => {
+                let mpro = c.iter().find(|x| x.name == ItemName::Name("dmap.protocolversion")).unwrap();
+                assert_eq!(mpro.value, DmapValue::Version(Version { major: 2, minor: 0, patch: 6 }));
+            }
+            _ => unreachable!(),
+        }
+
+        let item = DmapItem {
+            name: ItemName::Name("dmap.serverinforesponse"),
+            value: DmapValue::Container(vec![
+                DmapItem { name: ItemName::Name("dmap.utctime"), value: DmapValue::Timestamp(1500000000) },
+            ]),
+        };
+        let data = ser::to_vec(&parser, &item).unwrap();
+        verify_parse(&parser, &data);
+        let decoded: DmapItem = de::from_slice(&parser, &data).unwrap();
+        assert_eq!(decoded, item);
+        match decoded.value {
+            DmapValue::Container(c) => assert_eq!(c[0].value.as_system_time(),
+                                                  Some(UNIX_EPOCH + Duration::from_secs(1500000000))),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn serde_helpers() {
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Info<'a> {
+            #[serde(rename = "dmap.status")]
+            status: i32,
+            #[serde(rename = "dmap.itemname")]
+            name: &'a str,
+            #[serde(rename = "dmap.utctime", with = "serde_helpers::timestamp")]
+            time: SystemTime,
+            #[serde(rename = "dmap.protocolversion", with = "serde_helpers::version")]
+            version: Version,
+        }
+
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Response<'a> {
+            #[serde(rename = "dmap.serverinforesponse", borrow)]
+            info: Info<'a>,
+        }
+
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let info = Response { info: Info {
+            status: 200,
+            name: "foo",
+            time: UNIX_EPOCH + Duration::from_secs(1500000000),
+            version: Version { major: 3, minor: 0, patch: 8 },
+        } };
+        let data = ser::to_vec(&parser, &info).unwrap();
+        let item: DmapItem = de::from_slice(&parser, &data).unwrap();
+        assert_eq!(item.value, DmapValue::Container(vec![
+            DmapItem { name: ItemName::Name("dmap.status"), value: DmapValue::I32(200) },
+            DmapItem { name: ItemName::Name("dmap.itemname"), value: DmapValue::String("foo".into()) },
+            DmapItem { name: ItemName::Name("dmap.utctime"), value: DmapValue::Timestamp(1500000000) },
+            DmapItem { name: ItemName::Name("dmap.protocolversion"),
+                       value: DmapValue::Version(Version { major: 3, minor: 0, patch: 8 }) },
+        ]));
+        assert_eq!(de::from_slice::<Response>(&parser, &data).unwrap(), info);
+        assert_eq!(info.info.version.to_string(), "3.0.8");
+    }
+
+    #[cfg(all(feature = "chrono", feature = "time"))]
+    #[test]
+    fn serde_helpers_chrono_time() {
+        #[derive(Serialize, Deserialize, Debug, PartialEq)]
+        struct Info {
+            #[serde(rename = "dmap.utctime", with = "serde_helpers::chrono_timestamp")]
+            chrono: chrono::DateTime<chrono::Utc>,
+            #[serde(rename = "daap.songdateadded", with = "serde_helpers::time_timestamp")]
+            time: time::OffsetDateTime,
+        }
+
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let info = Info {
+            chrono: chrono::TimeZone::timestamp_opt(&chrono::Utc, 1500000000, 0).unwrap(),
+            time: time::OffsetDateTime::from_unix_timestamp(1500000001).unwrap(),
+        };
+        let data = ser::to_vec(&parser, &info).unwrap();
+        assert_eq!(de::from_slice::<Info>(&parser, &data).unwrap(), info);
+    }
+
+    #[test]
+    fn owned() {
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let data = include_bytes!("../testdata/server-info.bin");
+
+        let owned = {
+            let item: DmapItem = de::from_slice(&parser, data).unwrap();
+            item.into_owned()
+        };
+        let borrowed: DmapItem = de::from_slice(&parser, data).unwrap();
+        assert_eq!(owned.as_borrowed(), borrowed);
+
+        let decoded: OwnedDmapItem = de::from_slice(&parser, data).unwrap();
+        assert_eq!(decoded, owned);
+        assert_eq!(ser::to_vec(&parser, &owned).unwrap(), &data[..]);
+
+        // no borrows left, so this can go to another thread
+        let handle = ::std::thread::spawn(move || owned.name);
+        assert_eq!(handle.join().unwrap(), owned::OwnedItemName::Name("dmap.serverinforesponse".to_string()));
+    }
+
+    #[test]
+    fn reader() {
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        for data in &[&include_bytes!("../testdata/server-info.bin")[..], &include_bytes!("../testdata/login.bin")[..]] {
+            let expected: OwnedDmapItem = de::from_slice(&parser, data).unwrap();
+            let streamed: OwnedDmapItem = from_reader(&parser, *data).unwrap();
+            assert_eq!(streamed, expected);
+
+            let err = from_reader::<_, OwnedDmapItem>(&parser, &data[..data.len() - 1]).unwrap_err();
+            assert_eq!(*err.kind(), ErrorKind::Truncated);
+
+            let mut trailing = data.to_vec();
+            trailing.push(0);
+            let err = from_reader::<_, OwnedDmapItem>(&parser, &trailing[..]).unwrap_err();
+            assert_eq!(*err.kind(), ErrorKind::TrailingData);
+            assert_eq!(err.offset(), Some(data.len()));
+        }
+
+        // a bogus length must not be trusted for allocations
+        let err = from_reader::<_, OwnedDmapItem>(&parser, &b"minm\xff\xff\xff\xffabc"[..]).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Truncated);
+        assert_eq!(err.code(), Some(*b"minm"));
+    }
+
+    #[test]
+    fn writer() {
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        for data in &[&include_bytes!("../testdata/server-info.bin")[..], &include_bytes!("../testdata/login.bin")[..]] {
+            let item: DmapItem = de::from_slice(&parser, data).unwrap();
+            let mut out = Vec::new();
+            to_writer(&parser, &mut out, &item).unwrap();
+            assert_eq!(out, *data);
+        }
+
+        #[derive(Serialize)]
+        struct Inner {
+            #[serde(rename = "dmap.itemname")]
+            name: Option<&'static str>,
+            #[serde(rename = "dmap.itemid")]
+            id: i32,
+        }
+
+        #[derive(Serialize)]
+        struct Listing {
+            #[serde(rename = "dmap.status")]
+            status: Option<i32>,
+            #[serde(rename = "dmap.listingitem")]
+            items: Vec<Inner>,
+        }
+
+        // nested containers and skipped Nones must come out like to_vec
+        let listing = Listing {
+            status: None,
+            items: vec![Inner { name: Some("a"), id: 1 }, Inner { name: None, id: 2 }],
+        };
+        let mut out = Vec::new();
+        to_writer(&parser, &mut out, &listing).unwrap();
+        assert_eq!(out, ser::to_vec(&parser, &listing).unwrap());
+
+        let err = to_writer(&parser, Vec::new(), &vec![1, 2, 3]).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Unsupported("sequence outside of a map"));
+        // a value that grows between the passes doesn't fit its header
+        struct Growing(std::cell::Cell<usize>);
+        impl serde::Serialize for Growing {
+            fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                self.0.set(self.0.get() + 1);
+                s.serialize_str(&"x".repeat(self.0.get()))
+            }
+        }
+        #[derive(Serialize)]
+        struct Outer {
+            #[serde(rename = "dmap.listingitem")]
+            item: HashMap<&'static str, Growing>,
+        }
+        let outer = Outer { item: Some(("dmap.itemname", Growing(Default::default()))).into_iter().collect() };
+        let err = to_writer(&parser, Vec::new(), &outer).unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Message("value serialized differently on the second pass".to_string()));
+    }
+
+    #[test]
+    fn pull_reader() {
+        use reader::{Reader, Event};
+
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let data = include_bytes!("../testdata/login.bin");
+        let events = Reader::new(&parser, data).collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(events[0], Event::StartContainer(Code(*b"mlog"), data.len() as u32 - 8));
+        assert_eq!(events[1], Event::Leaf(Code(*b"mstt"), Some(TypeKind::I32), &[0, 0, 0, 200][..]));
+        assert_eq!(events.last(), Some(&Event::EndContainer));
+
+        let mut reader = Reader::new(&parser, data);
+        assert_eq!(reader.depth(), 0);
+        reader.next_event().unwrap();
+        assert_eq!(reader.depth(), 1);
+        reader.skip_container();
+        assert_eq!(reader.offset(), data.len());
+        assert_eq!(reader.next_event().unwrap(), None);
+
+        // a child claiming to be longer than its container
+        let mut bad = data.to_vec();
+        bad[12] = 1;
+        let err = Reader::new(&parser, &bad).find(|e| e.is_err()).unwrap().unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Truncated);
+        assert_eq!(err.code(), Some(*b"mstt"));
+    }
+
+    #[test]
+    fn push_writer() {
+        use writer::Writer;
+
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let mut writer = Writer::with_parser(&parser);
+        writer.begin_container(*b"mlog").unwrap()
+            .write_i32(*b"mstt", 200).unwrap()
+            .write_i32(*b"mlid", 0x3951d5bb).unwrap()
+            .end_container().unwrap();
+        assert_eq!(writer.finish().unwrap(), &include_bytes!("../testdata/login.bin")[..]);
+
+        let mut writer = Writer::with_parser(&parser);
+        let err = writer.write_str(*b"mstt", "200").err().unwrap();
+        assert_eq!(*err.kind(), ErrorKind::WrongKind(TypeKind::I32));
+        let err = writer.write_u8(*b"zzzz", 1).err().unwrap();
+        assert_eq!(*err.kind(), ErrorKind::UnknownTag);
+        assert_eq!(err.code(), Some(*b"zzzz"));
+        writer.write_bytes(*b"zzzz", &[1]).unwrap();
+        assert_eq!(*writer.end_container().err().unwrap().kind(), ErrorKind::UnbalancedContainer);
+        writer.begin_container(*b"mlog").unwrap();
+        assert_eq!(*writer.finish().err().unwrap().kind(), ErrorKind::UnbalancedContainer);
+
+        // without a parser anything goes
+        let mut writer = Writer::new();
+        writer.begin_container(*b"zzzz").unwrap().write_str(*b"mstt", "x").unwrap().end_container().unwrap();
+        assert_eq!(writer.finish().unwrap(), b"zzzz\0\0\0\x09mstt\0\0\0\x01x");
+    }
+
+    #[test]
+    fn lazy() {
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let data = include_bytes!("../testdata/server-info.bin");
+        let root = DmapRef::new(&parser, data).unwrap();
+        assert_eq!(root.name(), Some("dmap.serverinforesponse"));
+        assert!(root.is_container());
+        assert_eq!(root.get("dmap.status").unwrap().unwrap().value().unwrap(), DmapValue::I32(200));
+        assert!(root.get("dmap.nosuchthing").unwrap().is_none());
+        let item: DmapItem = de::from_slice(&parser, data).unwrap();
+        assert_eq!(root.item().unwrap(), item);
+
+        // children after the ones we look at are never decoded
+        let mut writer = writer::Writer::with_parser(&parser);
+        writer.begin_container(*b"mlcl").unwrap();
+        for i in 0..5 {
+            writer.begin_container(*b"mlit").unwrap().write_i32(*b"miid", i).unwrap().end_container().unwrap();
+        }
+        writer.write_bytes(*b"mlit", b"\xff\xff").unwrap();
+        writer.end_container().unwrap();
+        let data = writer.finish().unwrap();
+        let root = DmapRef::new(&parser, &data).unwrap();
+        let ids = root.children().take(5)
+            .map(|c| c.unwrap().get("dmap.itemid").unwrap().unwrap().value().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, (0..5).map(DmapValue::I32).collect::<Vec<_>>());
+        let err = root.item().unwrap_err();
+        assert_eq!(*err.kind(), ErrorKind::Truncated);
+        assert_eq!(err.path(), &["dmap.listing".to_string(), "dmap.listingitem".to_string()][..]);
+
+        assert_eq!(*DmapRef::new(&parser, &data[..data.len() - 1]).err().unwrap().kind(), ErrorKind::Truncated);
+    }
+
+    #[test]
+    fn query() {
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let mut writer = writer::Writer::with_parser(&parser);
+        writer.begin_container(*b"adbs").unwrap().write_i32(*b"mstt", 200).unwrap();
+        writer.begin_container(*b"mlcl").unwrap();
+        for (i, name) in ["one", "two"].iter().enumerate() {
+            writer.begin_container(*b"mlit").unwrap()
+                .write_i32(*b"miid", i as i32).unwrap()
+                .write_str(*b"minm", name).unwrap()
+                .write_bytes(*b"zzzz", &[1]).unwrap()
+                .end_container().unwrap();
+        }
+        writer.end_container().unwrap().end_container().unwrap();
+        let data = writer.finish().unwrap();
+        let root: DmapItem = de::from_slice(&parser, &data).unwrap();
+
+        assert_eq!(root.get("dmap.status").unwrap().value.as_i32(), Some(200));
+        assert_eq!(root.get("dmap.status").unwrap().value.as_u32(), None);
+        assert!(root.get("dmap.nosuchthing").is_none());
+        let first = root.path("daap.databasesongs/dmap.listing/dmap.listingitem").unwrap();
+        assert_eq!(first.get("dmap.itemname").unwrap().value.as_str(), Some("one"));
+        assert_eq!(first.get("zzzz").unwrap().value.as_bytes(), Some(&[1][..]));
+        assert!(root.path("dmap.listing").is_none());
+        assert!(root.value.path("dmap.listing/dmap.listingitem/dmap.itemid").is_some());
+
+        let names = root.value.path("dmap.listing").unwrap().get_all("dmap.listingitem")
+            .filter_map(|i| i.get("dmap.itemname")?.value.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["one", "two"]);
+        assert_eq!(root.get_all("dmap.listingitem").count(), 0);
+    }
+
+    #[test]
+    fn dump() {
+        use dump::{dump, dump_item, DumpOptions};
+
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let data = include_bytes!("../testdata/login.bin");
+        let expected = "\
+mlog dmap.loginresponse container [24]
+  mstt dmap.status i32 [4] 200
+  mlid dmap.sessionid i32 [4] 961664443
+";
+        assert_eq!(dump(&parser, data, &DumpOptions::default()), expected);
+        let login: DmapItem = de::from_slice(&parser, data).unwrap();
+        assert_eq!(dump_item(&parser, &login, &DumpOptions::default()), expected);
+        assert_eq!(DmapRef::new(&parser, data).unwrap().to_string(), expected);
+        // a decoded item doesn't know its codes
+        assert_eq!(login.to_string(), "\
+dmap.loginresponse container [24]
+  dmap.status i32 [4] 200
+  dmap.sessionid i32 [4] 961664443
+");
+        // codes come from the parser that is passed in, not the builtin list
+        let mut custom = Parser::bootstrap();
+        custom.insert(*b"xlog", "dmap.loginresponse", TypeKind::Container);
+        custom.remove(*b"mstt");
+        let options = DumpOptions { max_items: Some(1), ..DumpOptions::default() };
+        assert_eq!(dump_item(&custom, &login, &options), "\
+xlog dmap.loginresponse container [24]
+  ???? dmap.status i32 [4] 200
+  ... 1 more
+");
+
+        let options = DumpOptions { max_items: Some(1), ..DumpOptions::default() };
+        let expected = "\
+mlog dmap.loginresponse container [24]
+  mstt dmap.status i32 [4] 200
+  ... 1 more
+";
+        assert_eq!(dump(&parser, data, &options), expected);
+        assert_eq!(dump_item(&parser, &login, &options), expected);
+        let options = DumpOptions { max_depth: Some(0), ..DumpOptions::default() };
+        assert_eq!(dump(&parser, data, &options), "mlog dmap.loginresponse container [24] ...\n");
+
+        // unknown tags get a hex preview, broken input an error line
+        let mut data = b"zzzz\0\0\0\x03\x01\x02\x03".to_vec();
+        data.extend_from_slice(&include_bytes!("../testdata/login.bin")[..20]);
+        let options = DumpOptions { hex_preview: 2, ..DumpOptions::default() };
+        assert_eq!(dump(&parser, &data, &options), "\
+zzzz ? unknown [3] 01 02 ...
+! truncated input in 'mlog' at offset 11
+");
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    fn json() {
+        let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
+        let files: &[&[u8]] = &[
+            include_bytes!("../testdata/content-codes.bin"),
+            include_bytes!("../testdata/server-info.bin"),
+            include_bytes!("../testdata/login.bin"),
+        ];
+        for data in files {
+            let json = json::to_json(&parser, data).unwrap();
+            assert_eq!(json::from_json(&json).unwrap(), *data);
+            // also through text, with a dictionary that knows less
+            let text = serde_json::to_string(&json::to_json(&Parser::bootstrap(), data).unwrap()).unwrap();
+            assert_eq!(json::from_json(&serde_json::from_str(&text).unwrap()).unwrap(), *data);
+        }
+
+        let json = json::to_json(&parser, include_bytes!("../testdata/login.bin")).unwrap();
+        assert_eq!(json["value"][0], serde_json::json!({"code": "mstt", "name": "dmap.status", "type": "i32", "value": 200}));
+        let login: DmapItem = de::from_slice(&parser, include_bytes!("../testdata/login.bin")).unwrap();
+        assert_eq!(json::item_to_json(&parser, &login).unwrap(), json);
+
+        // unknown tags, bodies that don't fit the type, odd codes
+        let mut writer = writer::Writer::new();
+        writer.begin_container(*b"mlog").unwrap()
+            .write_bytes(*b"zzzz", b"\x00\x01\xfe\xff").unwrap()
+            .write_bytes(*b"mstt", b"\x00\xc8").unwrap()
+            .write_bytes(*b"minm", b"\xff").unwrap()
+            .write_u32(*b"f\x8dch", 1).unwrap()
+            .end_container().unwrap();
+        let data = writer.finish().unwrap();
+        let json = json::to_json(&parser, &data).unwrap();
+        assert_eq!(json["value"][0], serde_json::json!({"code": "zzzz", "type": "unknown", "value": "AAH+/w=="}));
+        assert_eq!(json["value"][1]["raw"], "AMg=");
+        assert_eq!(json::from_json(&json).unwrap(), data);
+
+        let err = json::from_json(&serde_json::json!({"code": "mstt", "type": "i8", "value": 200})).unwrap_err();
+        assert!(err.to_string().starts_with("value doesn't match the type"));
+    }
+
     #[test]
     fn serverinfo() {
         let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
@@ -222,15 +1506,16 @@ mod tests {
     fn login() {
         let parser = Parser::new(include_bytes!("../testdata/content-codes.bin"));
         let login = verify_parse(&parser, include_bytes!("../testdata/login.bin"));
-        println!("{:#?}", login);
         assert_eq!(login.name, ItemName::Name("dmap.loginresponse"));
         match login.value {
-            DmapValue::Container(c) => {
+            DmapValue::Container(ref c) => {
                 assert_eq!(c[0].name, ItemName::Name("dmap.status"));
                 assert_eq!(c[0].value, DmapValue::I32(200));
             }
             _ => unreachable!(),
         }
+        assert_eq!(login.get("dmap.status").and_then(|i| i.value.as_i32()), Some(200));
+        assert_eq!(login.path("dmap.loginresponse/dmap.sessionid").and_then(|i| i.value.as_i32()), Some(0x3951d5bb));
     }
 
     #[test]
@@ -0,0 +1,351 @@
+//! Variants of `DmapItem` and friends that own their data.
+//!
+//! Unlike `DmapItem` these don't borrow from the input buffer or the
+//! parser's dictionary, so they can be cached, sent to other threads or
+//! built from runtime strings.
+
+use serde::de;
+use serde::ser::{self, SerializeMap};
+
+use std::borrow::Cow;
+use std::fmt;
+
+use value::{DmapItem, DmapValue, ItemName, TypedTag, Version, VALUE_TOKEN};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedDmapItem {
+    pub name: OwnedItemName,
+    pub value: OwnedDmapValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedDmapValue {
+    I8(i8),
+    U8(u8),
+    I16(i16),
+    U16(u16),
+    I32(i32),
+    U32(u32),
+    I64(i64),
+    U64(u64),
+    /// Seconds since the unix epoch.
+    Timestamp(u32),
+    Version(Version),
+    String(String),
+    InvalidString(Vec<u8>),
+    Container(Vec<OwnedDmapItem>),
+    Unknown(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OwnedItemName {
+    Name(String),
+    Code([u8; 4]),
+}
+
+impl<'a, 'k> DmapItem<'a, 'k> {
+    pub fn into_owned(self) -> OwnedDmapItem {
+        OwnedDmapItem {
+            name: self.name.into_owned(),
+            value: self.value.into_owned(),
+        }
+    }
+}
+
+impl<'k> ItemName<'k> {
+    pub fn into_owned(self) -> OwnedItemName {
+        match self {
+            ItemName::Name(n) => OwnedItemName::Name(n.to_string()),
+            ItemName::Code(c) => OwnedItemName::Code(c),
+        }
+    }
+}
+
+impl<'a, 'k> DmapValue<'a, 'k> {
+    pub fn into_owned(self) -> OwnedDmapValue {
+        match self {
+            DmapValue::I8(x) => OwnedDmapValue::I8(x),
+            DmapValue::U8(x) => OwnedDmapValue::U8(x),
+            DmapValue::I16(x) => OwnedDmapValue::I16(x),
+            DmapValue::U16(x) => OwnedDmapValue::U16(x),
+            DmapValue::I32(x) => OwnedDmapValue::I32(x),
+            DmapValue::U32(x) => OwnedDmapValue::U32(x),
+            DmapValue::I64(x) => OwnedDmapValue::I64(x),
+            DmapValue::U64(x) => OwnedDmapValue::U64(x),
+            DmapValue::Timestamp(x) => OwnedDmapValue::Timestamp(x),
+            DmapValue::Version(v) => OwnedDmapValue::Version(v),
+            DmapValue::String(s) => OwnedDmapValue::String(s.into_owned()),
+            DmapValue::InvalidString(b) => OwnedDmapValue::InvalidString(b.to_vec()),
+            DmapValue::Container(c) => OwnedDmapValue::Container(c.into_iter().map(DmapItem::into_owned).collect()),
+            DmapValue::Unknown(b) => OwnedDmapValue::Unknown(b.to_vec()),
+        }
+    }
+}
+
+impl OwnedDmapItem {
+    pub fn as_borrowed(&self) -> DmapItem<'_, '_> {
+        DmapItem {
+            name: self.name.as_borrowed(),
+            value: self.value.as_borrowed(),
+        }
+    }
+}
+
+impl OwnedItemName {
+    pub fn as_borrowed(&self) -> ItemName<'_> {
+        match *self {
+            OwnedItemName::Name(ref n) => ItemName::Name(n),
+            OwnedItemName::Code(c) => ItemName::Code(c),
+        }
+    }
+}
+
+impl OwnedDmapValue {
+    pub fn as_borrowed(&self) -> DmapValue<'_, '_> {
+        match *self {
+            OwnedDmapValue::I8(x) => DmapValue::I8(x),
+            OwnedDmapValue::U8(x) => DmapValue::U8(x),
+            OwnedDmapValue::I16(x) => DmapValue::I16(x),
+            OwnedDmapValue::U16(x) => DmapValue::U16(x),
+            OwnedDmapValue::I32(x) => DmapValue::I32(x),
+            OwnedDmapValue::U32(x) => DmapValue::U32(x),
+            OwnedDmapValue::I64(x) => DmapValue::I64(x),
+            OwnedDmapValue::U64(x) => DmapValue::U64(x),
+            OwnedDmapValue::Timestamp(x) => DmapValue::Timestamp(x),
+            OwnedDmapValue::Version(v) => DmapValue::Version(v),
+            OwnedDmapValue::String(ref s) => DmapValue::String(Cow::Borrowed(s)),
+            OwnedDmapValue::InvalidString(ref b) => DmapValue::InvalidString(b),
+            OwnedDmapValue::Container(ref c) => DmapValue::Container(c.iter().map(OwnedDmapItem::as_borrowed).collect()),
+            OwnedDmapValue::Unknown(ref b) => DmapValue::Unknown(b),
+        }
+    }
+}
+
+impl<'de> de::Deserialize<'de> for OwnedDmapItem {
+    fn deserialize<D>(deserializer: D) -> Result<OwnedDmapItem, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        struct ItemVisitor;
+
+        impl<'de> de::Visitor<'de> for ItemVisitor {
+            type Value = OwnedDmapItem;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("any valid DMAP item")
+            }
+
+            fn visit_map<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
+                where V: de::MapAccess<'de>
+            {
+                use serde::de::Error;
+                if let Some((k, v)) = visitor.next_entry()? {
+                    Ok(OwnedDmapItem { name: k, value: v })
+                } else {
+                    Err(V::Error::custom("failed to get entry"))
+                }
+            }
+        }
+
+        deserializer.deserialize_map(ItemVisitor)
+    }
+}
+
+impl<'de> de::Deserialize<'de> for OwnedItemName {
+    fn deserialize<D>(deserializer: D) -> Result<OwnedItemName, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        struct ItemNameVisitor;
+
+        impl<'de> de::Visitor<'de> for ItemNameVisitor {
+            type Value = OwnedItemName;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a valid item name")
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(OwnedItemName::Name(value.to_string()))
+            }
+
+            fn visit_string<E>(self, value: String) -> Result<Self::Value, E> {
+                Ok(OwnedItemName::Name(value))
+            }
+
+            fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E>
+                where E: de::Error
+            {
+                if value.len() != 4 {
+                    return Err(E::invalid_length(value.len(), &self));
+                }
+                let mut buf = [0; 4];
+                buf.copy_from_slice(value);
+                Ok(OwnedItemName::Code(buf))
+            }
+        }
+
+        deserializer.deserialize_any(ItemNameVisitor)
+    }
+}
+
+impl<'de> de::Deserialize<'de> for OwnedDmapValue {
+    fn deserialize<D>(deserializer: D) -> Result<OwnedDmapValue, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        struct ValueVisitor;
+
+        impl<'de> de::Visitor<'de> for ValueVisitor {
+            type Value = OwnedDmapValue;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("any valid DMAP value")
+            }
+
+            fn visit_i8<E>(self, value: i8) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::I8(value))
+            }
+
+            fn visit_u8<E>(self, value: u8) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::U8(value))
+            }
+
+            fn visit_i16<E>(self, value: i16) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::I16(value))
+            }
+
+            fn visit_u16<E>(self, value: u16) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::U16(value))
+            }
+
+            fn visit_i32<E>(self, value: i32) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::I32(value))
+            }
+
+            fn visit_u32<E>(self, value: u32) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::U32(value))
+            }
+
+            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::I64(value))
+            }
+
+            fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::U64(value))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::String(value.to_string()))
+            }
+
+            fn visit_string<E>(self, value: String) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::String(value))
+            }
+
+            fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::Unknown(value.to_vec()))
+            }
+
+            fn visit_byte_buf<E>(self, value: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(OwnedDmapValue::Unknown(value))
+            }
+
+            fn visit_enum<V>(self, data: V) -> Result<Self::Value, V::Error>
+                where V: de::EnumAccess<'de>
+            {
+                use serde::de::VariantAccess;
+                Ok(match data.variant()? {
+                    (TypedTag::Timestamp, v) => OwnedDmapValue::Timestamp(v.newtype_variant()?),
+                    (TypedTag::Version, v) => OwnedDmapValue::Version(v.newtype_variant::<u32>()?.into()),
+                    (TypedTag::InvalidString, v) => OwnedDmapValue::InvalidString(v.newtype_variant::<ByteBuf>()?.0),
+                })
+            }
+
+            fn visit_newtype_struct<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+                where D: de::Deserializer<'de>
+            {
+                deserializer.deserialize_any(self)
+            }
+
+            fn visit_map<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
+                where V: de::MapAccess<'de>
+            {
+                let mut vec = Vec::new();
+
+                while let Some((k, v)) = visitor.next_entry()? {
+                    vec.push(OwnedDmapItem {
+                        name: k,
+                        value: v,
+                    });
+                }
+
+                Ok(OwnedDmapValue::Container(vec))
+            }
+        }
+
+        deserializer.deserialize_newtype_struct(VALUE_TOKEN, ValueVisitor)
+    }
+}
+
+impl ser::Serialize for OwnedDmapItem {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(&self.name, &self.value)?;
+        map.end()
+    }
+}
+
+impl ser::Serialize for OwnedItemName {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        self.as_borrowed().serialize(serializer)
+    }
+}
+
+impl ser::Serialize for OwnedDmapValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: ser::Serializer
+    {
+        match *self {
+            OwnedDmapValue::Container(ref c) => {
+                let mut map = serializer.serialize_map(Some(c.len()))?;
+                for e in c {
+                    map.serialize_entry(&e.name, &e.value)?;
+                }
+                map.end()
+            }
+            // only containers are expensive to borrow
+            ref x => x.as_borrowed().serialize(serializer),
+        }
+    }
+}
+
+// Vec<u8> deserializes from a sequence, this takes bytes
+struct ByteBuf(Vec<u8>);
+
+impl<'de> de::Deserialize<'de> for ByteBuf {
+    fn deserialize<D>(deserializer: D) -> Result<ByteBuf, D::Error>
+        where D: de::Deserializer<'de>
+    {
+        struct ByteBufVisitor;
+
+        impl<'de> de::Visitor<'de> for ByteBufVisitor {
+            type Value = ByteBuf;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("bytes")
+            }
+
+            fn visit_bytes<E>(self, value: &[u8]) -> Result<Self::Value, E> {
+                Ok(ByteBuf(value.to_vec()))
+            }
+
+            fn visit_byte_buf<E>(self, value: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(ByteBuf(value))
+            }
+        }
+
+        deserializer.deserialize_bytes(ByteBufVisitor)
+    }
+}
@@ -0,0 +1,351 @@
+//! Deserializing from a `std::io::Read`.
+//!
+//! Unlike `from_slice` this never holds more than a single leaf tag in
+//! memory, so huge listings can be decoded straight off a socket. Since
+//! nothing can be borrowed from the input, strings and byte strings are
+//! copied and `T` has to be `DeserializeOwned` (use `OwnedDmapItem` instead of
+//! `DmapItem`).
+
+use super::{Parser, ContentCode, TypeKind, Error, ErrorKind, DecodeOptions, StringPolicy, read_int, read_str};
+use de::TypedValue;
+use value::VALUE_TOKEN;
+use byteorder::{BigEndian, ByteOrder};
+use serde::de::{self, Visitor, DeserializeSeed, Deserialize, DeserializeOwned};
+use serde::de::value::{BytesDeserializer, StrDeserializer, MapAccessDeserializer, U32Deserializer};
+use std::borrow::Cow;
+use std::io::{self, Read};
+use std::str;
+
+pub fn from_reader<'a, 'k, R, T>(parser: &'a Parser<'k>, reader: R) -> Result<T, Error>
+    where R: Read, T: DeserializeOwned
+{
+    let mut deserializer = ReaderDeserializer::new(parser, reader);
+    let t = {
+        let mut map = deserializer.top_level();
+        T::deserialize(MapAccessDeserializer::new(&mut map))?
+    };
+    deserializer.end()?;
+    Ok(t)
+}
+
+/// Reads tags from `R` one at a time.
+struct ReaderDeserializer<'a, 'k: 'a, R> {
+    parser: &'a Parser<'k>,
+    reader: R,
+    offset: usize,
+    items: usize,
+    buf: Vec<u8>,
+}
+
+struct Header<'a, 'k: 'a> {
+    code: [u8; 4],
+    typedesc: Option<&'a ContentCode<'k>>,
+    len: usize,
+    offset: usize,
+}
+
+impl<'a, 'k: 'a, R: Read> ReaderDeserializer<'a, 'k, R> {
+    fn new(parser: &'a Parser<'k>, reader: R) -> ReaderDeserializer<'a, 'k, R> {
+        ReaderDeserializer {
+            parser,
+            reader,
+            offset: 0,
+            items: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    fn top_level<'r>(&'r mut self) -> ReaderMap<'r, 'a, 'k, R> {
+        ReaderMap { parent: self, end: None, depth: 0, current: None }
+    }
+
+    /// Makes sure the input ends here.
+    fn end(&mut self) -> Result<(), Error> {
+        let mut byte = [0];
+        match self.reader.read(&mut byte) {
+            Ok(0) => Ok(()),
+            Ok(_) => Err(Error::new(ErrorKind::TrailingData).at_offset(self.offset)),
+            Err(e) => Err(Error::io(e).at_offset(self.offset)),
+        }
+    }
+
+    fn read_header(&mut self, end: Option<usize>, depth: usize) -> Result<Option<Header<'a, 'k>>, Error> {
+        let offset = self.offset;
+        if end == Some(offset) {
+            return Ok(None);
+        }
+
+        let mut header = [0; 8];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.reader.read(&mut header[filled..]) {
+                // a clean end of input is only fine between top level tags
+                Ok(0) if filled == 0 && end.is_none() => return Ok(None),
+                Ok(0) => return Err(Error::new(ErrorKind::Truncated).at_offset(offset)),
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(Error::io(e).at_offset(offset)),
+            }
+        }
+        self.offset += 8;
+
+        let mut code = [0; 4];
+        code.copy_from_slice(&header[..4]);
+        let len = BigEndian::read_u32(&header[4..]) as usize;
+        if end.is_some_and(|end| self.offset + len > end) {
+            return Err(Error::new(ErrorKind::Truncated).at(offset, code));
+        }
+
+        let typedesc = self.parser.lookup_code(code);
+        self.items += 1;
+        self.parser.decode_options().limits.check(typedesc.map(|c| c.kind), len, depth, self.items)
+            .map_err(|e| e.at(offset, code))?;
+        Ok(Some(Header { code, typedesc, len, offset }))
+    }
+
+    /// Reads the body of a leaf tag into `self.buf`.
+    fn read_body(&mut self, header: &Header) -> Result<(), Error> {
+        self.buf.clear();
+        // don't trust the length for the allocation, read_to_end grows as data arrives
+        let read = (&mut self.reader).take(header.len as u64).read_to_end(&mut self.buf)
+            .map_err(|e| Error::io(e).at(header.offset, header.code))?;
+        if read != header.len {
+            return Err(Error::new(ErrorKind::Truncated).at(header.offset, header.code));
+        }
+        self.offset += header.len;
+        Ok(())
+    }
+
+    /// Skips whatever is left of a container body.
+    fn skip_to(&mut self, end: usize) -> Result<(), Error> {
+        let remaining = (end - self.offset) as u64;
+        let skipped = io::copy(&mut (&mut self.reader).take(remaining), &mut io::sink())
+            .map_err(|e| Error::io(e).at_offset(self.offset))?;
+        self.offset += skipped as usize;
+        if skipped != remaining {
+            return Err(Error::new(ErrorKind::Truncated).at_offset(self.offset));
+        }
+        Ok(())
+    }
+}
+
+/// The tags of one container (or of the top level if `end` is `None`).
+struct ReaderMap<'r, 'a: 'r, 'k: 'a, R: 'r> {
+    parent: &'r mut ReaderDeserializer<'a, 'k, R>,
+    end: Option<usize>,
+    depth: usize,
+    current: Option<Header<'a, 'k>>,
+}
+
+impl<'r, 'a, 'k, R: Read> ReaderMap<'r, 'a, 'k, R> {
+    fn next_header(&mut self) -> Result<Option<Header<'a, 'k>>, Error> {
+        match self.current.take() {
+            Some(x) => Ok(Some(x)),
+            None => self.parent.read_header(self.end, self.depth),
+        }
+    }
+
+    fn nested<'n>(&'n mut self, header: &Header) -> ReaderMap<'n, 'a, 'k, R> {
+        ReaderMap {
+            end: Some(header.offset + 8 + header.len),
+            depth: self.depth + 1,
+            parent: self.parent,
+            current: None,
+        }
+    }
+
+    /// Decodes a container body as a map and consumes whatever the visitor left over.
+    fn visit_container<'de, V>(&mut self, header: &Header<'a, 'k>, name: &str, v: V) -> Result<V::Value, Error>
+        where V: Visitor<'de>
+    {
+        let end = header.offset + 8 + header.len;
+        trace_container!(header.code, name, header.offset);
+        let value = v.visit_map(&mut self.nested(header)).map_err(|e| e.within(name))?;
+        self.parent.skip_to(end)?;
+        Ok(value)
+    }
+}
+
+impl<'de, 'r, 'a, 'k, R: Read> de::MapAccess<'de> for &mut ReaderMap<'r, 'a, 'k, R> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+        where K: DeserializeSeed<'de>
+    {
+        let header = match self.next_header()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        trace_tag!(header.code, header.typedesc.map(|c| c.name), header.offset);
+        let key = match header.typedesc {
+            Some(c) => seed.deserialize(StrDeserializer::new(c.name)),
+            None => seed.deserialize(BytesDeserializer::new(&header.code)),
+        };
+        self.current = Some(header);
+        key.map(Some)
+    }
+
+    fn next_value_seed<K>(&mut self, seed: K) -> Result<K::Value, Self::Error>
+        where K: DeserializeSeed<'de>
+    {
+        seed.deserialize(ValueDeserializer(self))
+    }
+}
+
+struct ValueDeserializer<'m, 'r: 'm, 'a: 'r, 'k: 'a, R: 'r>(&'m mut ReaderMap<'r, 'a, 'k, R>);
+
+impl<'de, 'm, 'r, 'a, 'k, R: Read> de::Deserializer<'de> for ValueDeserializer<'m, 'r, 'a, 'k, R> {
+    type Error = Error;
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf unit unit_struct tuple
+        tuple_struct identifier ignored_any
+    }
+
+    fn deserialize_any<V>(self, v: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        let header = self.0.current.take().unwrap();
+        let (code, offset) = (header.code, header.offset);
+        let result = match header.typedesc {
+            Some(c) if c.kind == TypeKind::Container => self.0.visit_container(&header, c.name, v),
+            Some(c) => {
+                self.0.parent.read_body(&header)?;
+                let body = &self.0.parent.buf[..];
+                let DecodeOptions { lengths, strings, .. } = self.0.parent.parser.decode_options();
+                let int = |kind| read_int(kind, body, lengths);
+                match c.kind {
+                    TypeKind::I8 => int(c.kind).and_then(|x| v.visit_i8(x as i8)),
+                    TypeKind::U8 => int(c.kind).and_then(|x| v.visit_u8(x as u8)),
+                    TypeKind::I16 => int(c.kind).and_then(|x| v.visit_i16(x as i16)),
+                    TypeKind::U16 => int(c.kind).and_then(|x| v.visit_u16(x as u16)),
+                    TypeKind::I32 => int(c.kind).and_then(|x| v.visit_i32(x as i32)),
+                    TypeKind::U32 | TypeKind::Timestamp | TypeKind::Version
+                        => int(c.kind).and_then(|x| v.visit_u32(x as u32)),
+                    TypeKind::I64 => int(c.kind).and_then(|x| v.visit_i64(x as i64)),
+                    TypeKind::U64 => int(c.kind).and_then(|x| v.visit_u64(x)),
+                    TypeKind::String => read_str(body, strings).and_then(|s| match s {
+                        Some(Cow::Borrowed(s)) => v.visit_str(s),
+                        Some(Cow::Owned(s)) => v.visit_string(s),
+                        None => v.visit_bytes(body),
+                    }),
+                    TypeKind::Container => unreachable!(),
+                }
+            }
+            None => {
+                self.0.parent.read_body(&header)?;
+                v.visit_bytes(&self.0.parent.buf)
+            }
+        };
+        result.map_err(|e| e.at(offset, code))
+    }
+
+    fn deserialize_newtype_struct<V>(self, name: &'static str, visitor: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        // same trick as de::ValueDeserializer, see there
+        let options = self.0.parent.parser.decode_options();
+        let variant = match self.0.current.as_ref().unwrap().typedesc {
+            Some(c) if name == VALUE_TOKEN && c.kind == TypeKind::Timestamp => "Timestamp",
+            Some(c) if name == VALUE_TOKEN && c.kind == TypeKind::Version => "Version",
+            Some(c) if name == VALUE_TOKEN && c.kind == TypeKind::String && options.strings == StringPolicy::Raw
+                => "InvalidString",
+            _ => return self.deserialize_any(visitor),
+        };
+        let header = self.0.current.take().unwrap();
+        self.0.parent.read_body(&header)?;
+        let body = &self.0.parent.buf[..];
+        let result = match variant {
+            // we only know whether it's invalid once we have the body
+            "InvalidString" => match str::from_utf8(body) {
+                Ok(s) => visitor.visit_str(s),
+                Err(_) => visitor.visit_enum(TypedValue { variant, value: BytesDeserializer::new(body) }),
+            },
+            _ => read_int(TypeKind::U32, body, options.lengths)
+                .and_then(|value| visitor.visit_enum(TypedValue { variant, value: U32Deserializer::new(value as u32) })),
+        };
+        result.map_err(|e| e.at(header.offset, header.code))
+    }
+
+    fn deserialize_enum<V>(self, _: &'static str, _: &'static [&'static str], visitor: V)
+        -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        // a plain serde enum, stored as the index of a unit variant
+        let (offset, code) = {
+            let header = self.0.current.as_ref().unwrap();
+            (header.offset, header.code)
+        };
+        let index = u32::deserialize(self)?;
+        visitor.visit_enum(U32Deserializer::<Error>::new(index)).map_err(|e| e.at(offset, code))
+    }
+
+    fn deserialize_map<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        {
+            let header = self.0.current.as_ref().unwrap();
+            if header.typedesc.is_none() {
+                return Err(Error::new(ErrorKind::UnknownTag).at(header.offset, header.code));
+            }
+        }
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_struct<V>(self, _: &'static str, _: &'static [&'static str], visitor: V)
+        -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+        where V: Visitor<'de>
+    {
+        let code = self.0.current.as_ref().unwrap().code;
+        visitor.visit_seq(SeqDeserializer { code, parent: self.0 })
+    }
+}
+
+struct SeqDeserializer<'m, 'r: 'm, 'a: 'r, 'k: 'a, R: 'r> {
+    parent: &'m mut ReaderMap<'r, 'a, 'k, R>,
+    code: [u8; 4],
+}
+
+impl<'de, 'm, 'r, 'a, 'k, R: Read> de::SeqAccess<'de> for SeqDeserializer<'m, 'r, 'a, 'k, R> {
+    type Error = Error;
+
+    fn next_element_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+        where K: DeserializeSeed<'de>
+    {
+        let header = match self.parent.next_header()? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        if header.code != self.code {
+            self.parent.current = Some(header);
+            return Ok(None);
+        }
+
+        let (code, offset) = (header.code, header.offset);
+        let name = match header.typedesc {
+            Some(c) => c.name,
+            None => return Err(Error::new(ErrorKind::UnknownTag).at(offset, code)),
+        };
+        let end = offset + 8 + header.len;
+        trace_container!(code, name, offset);
+        let value = seed.deserialize(MapAccessDeserializer::new(&mut self.parent.nested(&header)))
+            .map_err(|e| e.within(name).at(offset, code))?;
+        self.parent.parent.skip_to(end)?;
+        Ok(Some(value))
+    }
+}
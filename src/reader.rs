@@ -0,0 +1,123 @@
+//! A low level pull parser.
+//!
+//! `Reader` walks the tags of a buffer one at a time without building
+//! `DmapItem` trees, which is handy to pick a few tags out of a huge listing:
+//!
+//! ```no_run
+//! # let parser = dmap::Parser::builtin();
+//! # let data = &[][..];
+//! use dmap::reader::{Reader, Event};
+//!
+//! let mut reader = Reader::new(&parser, data);
+//! while let Some(event) = reader.next_event()? {
+//!     if let Event::Leaf(code, _, body) = event {
+//!         if code.0 == *b"minm" {
+//!             println!("{}", String::from_utf8_lossy(body));
+//!         }
+//!     }
+//! }
+//! # Ok::<(), dmap::Error>(())
+//! ```
+
+use super::{Parser, Code, TypeKind, Error};
+use de::read_header;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<'de> {
+    /// A container starts, its body is `u32` bytes long.
+    StartContainer(Code, u32),
+    /// A tag that is not a container. The kind is `None` for codes that are
+    /// not in the dictionary.
+    Leaf(Code, Option<TypeKind>, &'de [u8]),
+    /// The innermost open container ends.
+    EndContainer,
+}
+
+pub struct Reader<'a, 'k: 'a, 'de> {
+    parser: &'a Parser<'k>,
+    input: &'de [u8],
+    offset: usize,
+    items: usize,
+    /// End offsets of the open containers, innermost last.
+    ends: Vec<usize>,
+}
+
+impl<'a, 'k: 'a, 'de> Reader<'a, 'k, 'de> {
+    pub fn new(parser: &'a Parser<'k>, input: &'de [u8]) -> Reader<'a, 'k, 'de> {
+        Reader {
+            parser,
+            input,
+            offset: 0,
+            items: 0,
+            ends: Vec::new(),
+        }
+    }
+
+    /// Offset of the next tag in the input.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Number of containers we are currently inside of.
+    pub fn depth(&self) -> usize {
+        self.ends.len()
+    }
+
+    /// Returns the next event, or `None` at the end of the input.
+    pub fn next_event(&mut self) -> Result<Option<Event<'de>>, Error> {
+        let end = match self.ends.last() {
+            Some(&end) if end == self.offset => {
+                self.ends.pop();
+                return Ok(Some(Event::EndContainer));
+            }
+            Some(&end) => end,
+            None if self.offset == self.input.len() => return Ok(None),
+            None => self.input.len(),
+        };
+
+        // a tag must not reach past the end of its container
+        let input = self.input;
+        let tag = read_header(&input[self.offset..end], self.offset).and_then(|(code, body)| {
+            let kind = self.parser.lookup_code(code).map(|c| c.kind);
+            self.items += 1;
+            self.parser.decode_options().limits.check(kind, body.len(), self.ends.len(), self.items)
+                .map_err(|e| e.at(self.offset, code))?;
+            Ok((code, body, kind))
+        });
+        let (code, body, kind) = match tag {
+            Ok(x) => x,
+            Err(e) => {
+                // don't produce garbage after an error
+                self.offset = self.input.len();
+                self.ends.clear();
+                return Err(e);
+            }
+        };
+
+        if kind == Some(TypeKind::Container) {
+            self.offset += 8;
+            self.ends.push(self.offset + body.len());
+            Ok(Some(Event::StartContainer(Code(code), body.len() as u32)))
+        } else {
+            self.offset += 8 + body.len();
+            Ok(Some(Event::Leaf(Code(code), kind, body)))
+        }
+    }
+
+    /// Skips the rest of the innermost open container, including its
+    /// `EndContainer` event. Call it right after `StartContainer` to skip
+    /// the whole subtree. Does nothing at the top level.
+    pub fn skip_container(&mut self) {
+        if let Some(end) = self.ends.pop() {
+            self.offset = end;
+        }
+    }
+}
+
+impl<'a, 'k: 'a, 'de> Iterator for Reader<'a, 'k, 'de> {
+    type Item = Result<Event<'de>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event().transpose()
+    }
+}
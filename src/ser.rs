@@ -1,7 +1,10 @@
 use serde::ser::{self, Serialize, SerializeMap};
 use byteorder::{BigEndian, WriteBytesExt, ByteOrder};
 
-use super::Parser;
+use super::{Parser, TypeKind, Error, ErrorKind};
+use value;
+
+use std::mem;
 
 pub fn to_vec<'a, 'k, T>(parser: &'a Parser<'k>, value: &T) -> Result<Vec<u8>, Error>
     where T: Serialize + ?Sized
@@ -14,15 +17,31 @@ pub fn to_vec<'a, 'k, T>(parser: &'a Parser<'k>, value: &T) -> Result<Vec<u8>, E
 pub struct Serializer<'a, 'k: 'a> {
     parser: &'a Parser<'k>,
     output: Vec<u8>,
+    /// Code of the map key whose value comes next.
+    pending: Option<[u8; 4]>,
+    /// The next `i64` is a `dmap_enum!` discriminant.
+    discriminant: bool,
 }
 
 impl<'a, 'k> Serializer<'a, 'k> {
     pub fn new(parser: &'a Parser<'k>) -> Serializer<'a, 'k> {
-        Serializer { output: Vec::new(), parser }
+        Serializer { output: Vec::new(), parser, pending: None, discriminant: false }
     }
-}
 
-type Error = ::serde::de::value::Error;
+    fn serialize_discriminant(&mut self, v: i64) -> Result<(), Error> {
+        let code = self.pending.ok_or_else(|| unsupported("enum outside of a map"))?;
+        let body = discriminant(self.parser, code, v)?;
+        self.leaf(&body)
+    }
+
+    // the key's code is already in the output, add the length and body
+    fn leaf(&mut self, body: &[u8]) -> Result<(), Error> {
+        self.pending.take().ok_or_else(|| unsupported("value outside of a map"))?;
+        self.output.write_u32::<BigEndian>(body.len() as u32).unwrap();
+        self.output.extend_from_slice(body);
+        Ok(())
+    }
+}
 
 impl<'a, 'k: 'a, 'b> ser::Serializer for &'b mut Serializer<'a, 'k> {
     type Ok = ();
@@ -35,28 +54,26 @@ impl<'a, 'k: 'a, 'b> ser::Serializer for &'b mut Serializer<'a, 'k> {
     type SerializeStruct = MapSerializer<'a, 'k, 'b>;
     type SerializeStructVariant = ser::Impossible<Self::Ok, Self::Error>;
 
-    fn serialize_bool(self, _: bool) -> Result<(), Error> { panic!("not supported"); }
-    fn serialize_f32(self, _: f32) -> Result<(), Error> { panic!("not supported"); }
-    fn serialize_f64(self, _: f64) -> Result<(), Error> { panic!("not supported"); }
-    fn serialize_char(self, _: char) -> Result<(), Error> { panic!("not supported"); }
-    fn serialize_unit(self) -> Result<(), Error> { panic!("not supported"); }
-    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> { panic!("not supported"); }
-    fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Result<(), Error> { panic!("not supported"); }
-    fn serialize_newtype_struct<T: ?Sized>(self, _: &'static str, _: &T) -> Result<(), Error> { panic!("not supported"); }
-    fn serialize_newtype_variant<T: ?Sized>(self, _: &'static str, _: u32, _: &'static str, _: &T) -> Result<(), Error> { panic!("not supported"); }
-    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> { panic!("not supported"); }
-    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, Error> { panic!("not supported"); }
-    fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeTupleVariant, Error> { panic!("not supported"); }
-    fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeStructVariant, Error> { panic!("not supported"); }
+    fn serialize_bool(self, _: bool) -> Result<(), Error> { Err(unsupported("bool")) }
+    fn serialize_f32(self, _: f32) -> Result<(), Error> { Err(unsupported("f32")) }
+    fn serialize_f64(self, _: f64) -> Result<(), Error> { Err(unsupported("f64")) }
+    fn serialize_char(self, _: char) -> Result<(), Error> { Err(unsupported("char")) }
+    fn serialize_unit(self) -> Result<(), Error> { Err(unsupported("unit")) }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> { Err(unsupported("unit struct")) }
+    fn serialize_newtype_variant<T: ?Sized>(self, _: &'static str, _: u32, _: &'static str, _: &T) -> Result<(), Error> { Err(unsupported("enum")) }
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> { Err(unsupported("tuple")) }
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, Error> { Err(unsupported("tuple struct")) }
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeTupleVariant, Error> { Err(unsupported("enum")) }
+    fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeStructVariant, Error> { Err(unsupported("enum")) }
 
     fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
-        let mut buf = [0; 4];
-        let offset = self.output.len() - 4;
-        buf.copy_from_slice(&mut self.output[offset..]);
-        self.output.truncate(offset);
+        // the tag was already written by the map, we repeat it for every element
+        let code = self.pending.take().ok_or_else(|| unsupported("sequence outside of a map"))?;
+        let len = self.output.len();
+        self.output.truncate(len - 4);
         Ok(SeqSerializer {
             parent: self,
-            code: buf,
+            code,
         })
     }
 
@@ -79,69 +96,126 @@ impl<'a, 'k: 'a, 'b> ser::Serializer for &'b mut Serializer<'a, 'k> {
 
     fn serialize_none(self) -> Result<(), Error> {
         // hack: pretend we never wrote that tag by removing it
-        let len = self.output.len() - 4;
-        self.output.truncate(len);
+        self.pending.take().ok_or_else(|| unsupported("none outside of a map"))?;
+        let len = self.output.len();
+        self.output.truncate(len - 4);
         Ok(())
     }
 
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<(), Error>
+        where T: Serialize + ?Sized
+    {
+        if name == value::TIMESTAMP_TOKEN || name == value::VERSION_TOKEN {
+            value.serialize(self)
+        } else if name == value::ENUM_TOKEN {
+            self.discriminant = true;
+            let result = value.serialize(&mut *self);
+            self.discriminant = false;
+            result
+        } else {
+            Err(unsupported("newtype struct"))
+        }
+    }
+
     fn serialize_some<T>(self, t: &T) -> Result<(), Error>
         where T: Serialize + ?Sized
     {
         t.serialize(self)
     }
 
+    fn serialize_unit_variant(self, _: &'static str, index: u32, _: &'static str) -> Result<(), Error> {
+        self.serialize_discriminant(i64::from(index))
+    }
+
     fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
-        self.output.write_u32::<BigEndian>(v.len() as u32).unwrap();
-        self.output.extend_from_slice(v);
-        Ok(())
+        self.leaf(v)
     }
 
     fn serialize_str(self, v: &str) -> Result<(), Error> {
-        self.serialize_bytes(v.as_bytes())
+        self.leaf(v.as_bytes())
     }
 
     fn serialize_i8(self, v: i8) -> Result<(), Error> {
-        self.serialize_bytes(&[v as u8])
+        self.leaf(&[v as u8])
     }
 
     fn serialize_u8(self, v: u8) -> Result<(), Error> {
-        self.serialize_bytes(&[v])
+        self.leaf(&[v])
     }
 
     fn serialize_i16(self, v: i16) -> Result<(), Error> {
-        self.output.write_u32::<BigEndian>(2).unwrap();
-        self.output.write_i16::<BigEndian>(v).unwrap();
-        Ok(())
+        let mut buf = [0; 2];
+        BigEndian::write_i16(&mut buf, v);
+        self.leaf(&buf)
     }
 
     fn serialize_u16(self, v: u16) -> Result<(), Error> {
-        self.output.write_u32::<BigEndian>(2).unwrap();
-        self.output.write_u16::<BigEndian>(v).unwrap();
-        Ok(())
+        let mut buf = [0; 2];
+        BigEndian::write_u16(&mut buf, v);
+        self.leaf(&buf)
     }
 
     fn serialize_i32(self, v: i32) -> Result<(), Error> {
-        self.output.write_u32::<BigEndian>(4).unwrap();
-        self.output.write_i32::<BigEndian>(v).unwrap();
-        Ok(())
+        let mut buf = [0; 4];
+        BigEndian::write_i32(&mut buf, v);
+        self.leaf(&buf)
     }
 
     fn serialize_u32(self, v: u32) -> Result<(), Error> {
-        self.output.write_u32::<BigEndian>(4).unwrap();
-        self.output.write_u32::<BigEndian>(v).unwrap();
-        Ok(())
+        let mut buf = [0; 4];
+        BigEndian::write_u32(&mut buf, v);
+        self.leaf(&buf)
     }
 
     fn serialize_i64(self, v: i64) -> Result<(), Error> {
-        self.output.write_u32::<BigEndian>(8).unwrap();
-        self.output.write_i64::<BigEndian>(v).unwrap();
-        Ok(())
+        if mem::replace(&mut self.discriminant, false) {
+            return self.serialize_discriminant(v);
+        }
+        let mut buf = [0; 8];
+        BigEndian::write_i64(&mut buf, v);
+        self.leaf(&buf)
     }
 
     fn serialize_u64(self, v: u64) -> Result<(), Error> {
-        self.output.write_u32::<BigEndian>(8).unwrap();
-        self.output.write_u64::<BigEndian>(v).unwrap();
-        Ok(())
+        let mut buf = [0; 8];
+        BigEndian::write_u64(&mut buf, v);
+        self.leaf(&buf)
+    }
+}
+
+pub(crate) fn unsupported(what: &'static str) -> Error {
+    Error::new(ErrorKind::Unsupported(what))
+}
+
+/// The body of an enum discriminant under `code`, as wide as the code's type.
+pub(crate) fn discriminant(parser: &Parser, code: [u8; 4], v: i64) -> Result<Vec<u8>, Error> {
+    let kind = parser.lookup_code(code).map(|c| c.kind).ok_or_else(|| Error::new(ErrorKind::UnknownTag))?;
+    let wrong_kind = || Error::new(ErrorKind::WrongKind(kind));
+    let bits = 8 * kind.width().ok_or_else(wrong_kind)? as u32;
+    let signed = matches!(kind, TypeKind::I8 | TypeKind::I16 | TypeKind::I32 | TypeKind::I64);
+    let fits = if signed {
+        bits == 64 || (v >= -(1 << (bits - 1)) && v < 1 << (bits - 1))
+    } else {
+        v >= 0 && (bits == 64 || v < 1 << bits)
+    };
+    if !fits {
+        return Err(wrong_kind());
+    }
+    let mut body = vec![0; bits as usize / 8];
+    BigEndian::write_uint(&mut body, v as u64 & (u64::MAX >> (64 - bits)), bits as usize / 8);
+    Ok(body)
+}
+
+/// Resolves a map key (a content name or a raw 4-byte code) to its code.
+pub(crate) fn key_code<T>(parser: &Parser, key: &T) -> Result<[u8; 4], Error>
+    where T: Serialize + ?Sized
+{
+    match key.serialize(StringExtractor)? {
+        Ok(name) => match parser.lookup_name(&name) {
+            Some(c) => Ok(c.code),
+            None => Err(Error::new(ErrorKind::UnknownName(name))),
+        },
+        Err(c) => Ok(c),
     }
 }
 
@@ -158,6 +232,7 @@ impl<'a: 'b, 'k: 'a, 'b> ser::SerializeSeq for SeqSerializer<'a, 'k, 'b> {
         where T: Serialize + ?Sized,
     {
         self.parent.output.extend(&self.code);
+        self.parent.pending = Some(self.code);
         value.serialize(&mut *self.parent)
     }
 
@@ -178,11 +253,9 @@ impl<'a: 'b, 'k: 'a, 'b> ser::SerializeMap for MapSerializer<'a, 'k, 'b> {
     fn serialize_key<T>(&mut self, value: &T) -> Result<(), Self::Error>
         where T: Serialize + ?Sized
     {
-        let code = match value.serialize(StringExtractor)? {
-            Ok(name) => self.parent.parser.types.iter().find(|x| x.name == name).unwrap().code, // fixme unwrap
-            Err(c) => c,
-        };
+        let code = key_code(self.parent.parser, value)?;
         self.parent.output.extend_from_slice(&code);
+        self.parent.pending = Some(code);
         Ok(())
     }
 
@@ -236,51 +309,51 @@ impl ser::Serializer for StringExtractor {
     type SerializeStructVariant = ser::Impossible<Self::Ok, Error>;
 
     fn serialize_bool(self, _v: bool) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_i8(self, _v: i8) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_i16(self, _v: i16) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_i32(self, _v: i32) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_i64(self, _v: i64) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_u8(self, _v: u8) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_u16(self, _v: u16) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_u32(self, _v: u32) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_u64(self, _v: u64) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_f32(self, _v: f32) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_f64(self, _v: f64) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_char(self, _v: char) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_str(self, value: &str) -> Result<Self::Ok, Self::Error> {
@@ -288,29 +361,32 @@ impl ser::Serializer for StringExtractor {
     }
 
     fn serialize_bytes(self, value: &[u8]) -> Result<Self::Ok, Self::Error> {
+        if value.len() != 4 {
+            return Err(Error::new(ErrorKind::KeyNotString));
+        }
         let mut buf = [0; 4];
         buf.copy_from_slice(value);
         Ok(Err(buf))
     }
 
     fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
-    fn serialize_some<T: ?Sized>(self, _value: &T) -> Result<Self::Ok, Self::Error>
-        where T: ser::Serialize
+    fn serialize_some<T>(self, _value: &T) -> Result<Self::Ok, Self::Error>
+        where T: ser::Serialize + ?Sized
     {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_unit_struct(self,
                              _name: &'static str)
                              -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_unit_variant(self,
@@ -318,40 +394,40 @@ impl ser::Serializer for StringExtractor {
                               _variant_index: u32,
                               _variant: &'static str)
                               -> Result<Self::Ok, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
-    fn serialize_newtype_struct<T: ?Sized>(self, _name: &'static str, _value: &T)
-                                           -> Result<Self::Ok, Self::Error>
-        where T: ser::Serialize,
+    fn serialize_newtype_struct<T>(self, _name: &'static str, _value: &T)
+                                   -> Result<Self::Ok, Self::Error>
+        where T: ser::Serialize + ?Sized,
     {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(self,
-                                            _name: &'static str,
-                                            _variant_index: u32,
-                                            _variant: &'static str,
-                                            _value: &T)
-                                            -> Result<Self::Ok, Self::Error>
-        where T: ser::Serialize,
+    fn serialize_newtype_variant<T>(self,
+                                    _name: &'static str,
+                                    _variant_index: u32,
+                                    _variant: &'static str,
+                                    _value: &T)
+                                    -> Result<Self::Ok, Self::Error>
+        where T: ser::Serialize + ?Sized,
     {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_seq(self, _len: Option<usize>)
                      -> Result<Self::SerializeSeq, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_tuple(self, _len: usize)
                        -> Result<Self::SerializeTuple, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_tuple_struct(self, _name: &'static str, _len: usize)
                               -> Result<Self::SerializeTupleStruct, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_tuple_variant(self,
@@ -360,17 +436,17 @@ impl ser::Serializer for StringExtractor {
                                _variant: &'static str,
                                _len: usize)
                                -> Result<Self::SerializeTupleVariant, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_map(self, _len: Option<usize>)
                      -> Result<Self::SerializeMap, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_struct(self, _name: &'static str, _len: usize)
                         -> Result<Self::SerializeStruct, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 
     fn serialize_struct_variant(self,
@@ -379,6 +455,6 @@ impl ser::Serializer for StringExtractor {
                                 _variant: &'static str,
                                 _len: usize)
                                 -> Result<Self::SerializeStructVariant, Self::Error> {
-        panic!("key not string");
+        Err(Error::new(ErrorKind::KeyNotString))
     }
 }
@@ -0,0 +1,110 @@
+//! Helpers for `#[serde(with = "...")]` on DMAP timestamp and version fields.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Song {
+//!     #[serde(rename = "daap.songdateadded", with = "dmap::serde_helpers::timestamp")]
+//!     added: SystemTime,
+//!     #[serde(rename = "daap.protocolversion", with = "dmap::serde_helpers::version")]
+//!     version: dmap::Version,
+//! }
+//! ```
+//!
+//! DMAP timestamps are seconds since the unix epoch in 32 bits, times that
+//! don't fit are rejected when serializing.
+
+use serde::Serializer;
+use serde::ser::Error;
+use value::TIMESTAMP_TOKEN;
+
+fn serialize_secs<S>(secs: Option<u64>, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    match secs {
+        Some(secs) if secs <= u64::from(u32::MAX) =>
+            serializer.serialize_newtype_struct(TIMESTAMP_TOKEN, &(secs as u32)),
+        _ => Err(S::Error::custom("time is out of range for a DMAP timestamp")),
+    }
+}
+
+/// `std::time::SystemTime` as a DMAP timestamp.
+pub mod timestamp {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub fn serialize<S>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        super::serialize_secs(time.duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs()), serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<SystemTime, D::Error>
+        where D: Deserializer<'de>
+    {
+        let secs = u32::deserialize(deserializer)?;
+        Ok(UNIX_EPOCH + Duration::from_secs(secs.into()))
+    }
+}
+
+/// `dmap::Version` as a DMAP version.
+pub mod version {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use value::{Version, VERSION_TOKEN};
+
+    pub fn serialize<S>(version: &Version, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_newtype_struct(VERSION_TOKEN, &u32::from(*version))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Version, D::Error>
+        where D: Deserializer<'de>
+    {
+        u32::deserialize(deserializer).map(Version::from)
+    }
+}
+
+/// `chrono::DateTime<Utc>` as a DMAP timestamp.
+#[cfg(feature = "chrono")]
+pub mod chrono_timestamp {
+    use chrono::{DateTime, TimeZone, Utc};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+
+    pub fn serialize<S>(time: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let secs = time.timestamp();
+        super::serialize_secs(if secs >= 0 { Some(secs as u64) } else { None }, serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+        where D: Deserializer<'de>
+    {
+        let secs = u32::deserialize(deserializer)?;
+        Utc.timestamp_opt(secs.into(), 0).single()
+            .ok_or_else(|| D::Error::custom("invalid timestamp"))
+    }
+}
+
+/// `time::OffsetDateTime` as a DMAP timestamp.
+#[cfg(feature = "time")]
+pub mod time_timestamp {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+    use time::OffsetDateTime;
+
+    pub fn serialize<S>(time: &OffsetDateTime, serializer: S) -> Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        let secs = time.unix_timestamp();
+        super::serialize_secs(if secs >= 0 { Some(secs as u64) } else { None }, serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<OffsetDateTime, D::Error>
+        where D: Deserializer<'de>
+    {
+        let secs = u32::deserialize(deserializer)?;
+        OffsetDateTime::from_unix_timestamp(secs.into()).map_err(D::Error::custom)
+    }
+}
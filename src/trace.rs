@@ -0,0 +1,23 @@
+// Debug output of the deserializers, sent to `log` and/or `tracing` if those
+// features are enabled. Without them these expand to nothing.
+
+// one event per tag
+macro_rules! trace_tag {
+    ($code:expr, $name:expr, $offset:expr) => {
+        #[cfg(feature = "log")]
+        log::debug!("tag {} {:?} at offset {}", ::Code($code), $name, $offset);
+        #[cfg(feature = "tracing")]
+        tracing::debug!(code = %::Code($code), name = ?$name, offset = $offset, "tag");
+    };
+}
+
+// a span covering a container (log only gets an event when it starts),
+// lasts until the end of the enclosing block
+macro_rules! trace_container {
+    ($code:expr, $name:expr, $offset:expr) => {
+        #[cfg(feature = "log")]
+        log::debug!("container {} {} at offset {}", ::Code($code), $name, $offset);
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("container", code = %::Code($code), name = $name, offset = $offset).entered();
+    };
+}
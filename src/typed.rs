@@ -0,0 +1,434 @@
+//! Runtime support for `#[derive(DmapEncode, DmapDecode)]` (see the
+//! `dmap-derive` crate).
+//!
+//! Derived structs know the code and type of every field at compile time,
+//! so they are encoded and decoded without looking anything up in a
+//! dictionary:
+//!
+//! ```ignore
+//! #[derive(DmapEncode, DmapDecode)]
+//! #[dmap(code = "mlog")]
+//! struct Login {
+//!     #[dmap(code = "mstt", kind = "i32")]
+//!     status: i32,
+//!     #[dmap(code = "mlid", kind = "i32")]
+//!     session_id: u32,
+//! }
+//!
+//! let data = login.encode()?;
+//! let login = Login::decode(&Parser::empty(), &data)?;
+//! ```
+//!
+//! Fields can be integers (the Rust type and the `kind` may differ as long as
+//! the value fits), `String`, `&str`, `Cow<str>`, `Version`, `&[u8]` (the raw
+//! body, for any kind) and other derived structs (`kind = "container"`), or
+//! an `Option` or `Vec` of those. `Option` fields are left out when `None`,
+//! `Vec` fields are written as one tag per element. Tags the struct doesn't
+//! know are skipped, a missing required field is an error. A field whose type
+//! can't have its `kind` (`EncodeAs` and `DecodeAs`) doesn't compile.
+//!
+//! The encoding is plain DMAP, so derived structs read data written from a
+//! `DmapItem` and the other way around.
+
+use serde::de::Error as DeError;
+
+use super::{Parser, Code, TypeKind, Error, ErrorKind, DecodeOptions, read_int, read_str};
+use de::read_header;
+use value::Version;
+use writer::Writer;
+
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::convert::TryFrom;
+
+pub trait DmapEncode {
+    /// Code of the container the struct is written as.
+    const CODE: [u8; 4];
+
+    /// Writes the fields, without the surrounding container.
+    fn encode_fields(&self, writer: &mut Writer) -> Result<(), Error>;
+
+    /// Encodes the struct as a single container tag.
+    fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = Writer::new();
+        writer.begin_container(Self::CODE)?;
+        self.encode_fields(&mut writer)?;
+        writer.end_container()?;
+        writer.finish()
+    }
+}
+
+pub trait DmapDecode<'de>: Sized {
+    /// Code of the container the struct is read from.
+    const CODE: [u8; 4];
+
+    /// Reads the struct from the children of its container.
+    fn decode_fields(fields: Fields<'_, 'de>) -> Result<Self, Error>;
+
+    /// Decodes the single container tag in `input`. Only the parser's
+    /// `DecodeOptions` are used, its dictionary can be empty.
+    fn decode(parser: &Parser, input: &'de [u8]) -> Result<Self, Error> {
+        let items = Cell::new(0);
+        let mut top = Fields { options: parser.decode_options(), tail: input, offset: 0, depth: 0, items: &items };
+        let tag = top.next().unwrap_or_else(|| Err(Error::new(ErrorKind::Truncated).at_offset(0)))?;
+        if !top.tail.is_empty() {
+            return Err(Error::new(ErrorKind::TrailingData).at_offset(top.offset));
+        }
+        if tag.code != Self::CODE {
+            return Err(Error::custom(format!("expected a '{}' container", Code(Self::CODE))).at(0, tag.code));
+        }
+        Self::decode_fields(tag.children(TypeKind::Container)?)
+    }
+}
+
+/// A value that can be a field of a derived struct.
+pub trait EncodeField {
+    fn encode_field(&self, writer: &mut Writer, code: Code, kind: TypeKind) -> Result<(), Error>;
+}
+
+/// A value that can be a field of a derived struct.
+pub trait DecodeField<'de>: Sized {
+    fn decode_field(tag: Tag<'_, 'de>, kind: TypeKind) -> Result<Self, Error>;
+
+    /// Called for every tag with the field's code, by default the first one wins.
+    fn merge(slot: &mut Option<Self>, tag: Tag<'_, 'de>, kind: TypeKind) -> Result<(), Error> {
+        if slot.is_none() {
+            *slot = Some(Self::decode_field(tag, kind)?);
+        }
+        Ok(())
+    }
+
+    /// The value if the field's code never showed up, `None` makes it an error.
+    fn missing() -> Option<Self> {
+        None
+    }
+}
+
+/// Implemented for the field types that can be encoded as kind `K` (one of
+/// the types in `kinds`), the derive checks it for every field.
+pub trait EncodeAs<K> {}
+
+/// Implemented for the field types that can be decoded from kind `K`.
+pub trait DecodeAs<K> {}
+
+/// The `kind`s of derived fields, as types.
+pub mod kinds {
+    pub struct I8;
+    pub struct U8;
+    pub struct I16;
+    pub struct U16;
+    pub struct I32;
+    pub struct U32;
+    pub struct I64;
+    pub struct U64;
+    pub struct String;
+    pub struct Timestamp;
+    pub struct Version;
+    pub struct Container;
+}
+
+#[doc(hidden)]
+pub fn encode_as<K, T: EncodeAs<K> + ?Sized>() {}
+
+#[doc(hidden)]
+pub fn decode_as<K, T: DecodeAs<K>>() {}
+
+/// A tag inside a derived struct's container.
+#[derive(Clone, Copy)]
+pub struct Tag<'a, 'de> {
+    code: [u8; 4],
+    body: &'de [u8],
+    offset: usize,
+    depth: usize,
+    options: DecodeOptions,
+    items: &'a Cell<usize>,
+}
+
+impl<'a, 'de> Tag<'a, 'de> {
+    pub fn code(&self) -> Code {
+        Code(self.code)
+    }
+
+    /// The raw body.
+    pub fn body(&self) -> &'de [u8] {
+        self.body
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// The children, if `kind` is `TypeKind::Container`.
+    pub fn children(&self, kind: TypeKind) -> Result<Fields<'a, 'de>, Error> {
+        self.expect(kind, &[TypeKind::Container])?;
+        Ok(Fields {
+            options: self.options,
+            tail: self.body,
+            offset: self.offset + 8,
+            depth: self.depth + 1,
+            items: self.items,
+        })
+    }
+
+    fn expect(&self, kind: TypeKind, allowed: &[TypeKind]) -> Result<(), Error> {
+        if !allowed.contains(&kind) {
+            return Err(Error::new(ErrorKind::WrongKind(kind)).at(self.offset, self.code));
+        }
+        self.options.limits.check(Some(kind), self.body.len(), self.depth, self.items.get())
+            .map_err(|e| e.at(self.offset, self.code))
+    }
+
+    fn int<T: TryFrom<i128>>(&self, kind: TypeKind) -> Result<T, Error> {
+        self.expect(kind, INT_KINDS)?;
+        let value = read_int(kind, self.body, self.options.lengths).map(|bits| {
+            let shift = 128 - 8 * kind.width().unwrap() as u32;
+            match kind {
+                TypeKind::I8 | TypeKind::I16 | TypeKind::I32 | TypeKind::I64 => ((bits as i128) << shift) >> shift,
+                _ => bits as i128,
+            }
+        });
+        value.and_then(|v| T::try_from(v).map_err(|_| Error::new(ErrorKind::WrongKind(kind))))
+            .map_err(|e| e.at(self.offset, self.code))
+    }
+
+    fn str(&self, kind: TypeKind) -> Result<Cow<'de, str>, Error> {
+        self.expect(kind, &[TypeKind::String])?;
+        match read_str(self.body, self.options.strings) {
+            Ok(Some(s)) => Ok(s),
+            // StringPolicy::Raw, but there is no place for the bytes
+            Ok(None) => Err(Error::new(ErrorKind::InvalidUtf8).at(self.offset, self.code)),
+            Err(e) => Err(e.at(self.offset, self.code)),
+        }
+    }
+}
+
+/// Iterator over the children of a derived struct's container.
+pub struct Fields<'a, 'de> {
+    options: DecodeOptions,
+    tail: &'de [u8],
+    offset: usize,
+    depth: usize,
+    items: &'a Cell<usize>,
+}
+
+impl<'a, 'de> Iterator for Fields<'a, 'de> {
+    type Item = Result<Tag<'a, 'de>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.tail.is_empty() {
+            return None;
+        }
+        let offset = self.offset;
+        let tag = read_header(self.tail, offset).and_then(|(code, body)| {
+            self.items.set(self.items.get() + 1);
+            // the rest is checked with the field's kind, see `Tag::expect`
+            self.options.limits.check_items(self.items.get()).map_err(|e| e.at(offset, code))?;
+            Ok(Tag { code, body, offset, depth: self.depth, options: self.options, items: self.items })
+        });
+        match tag {
+            Ok(ref t) => {
+                self.tail = &self.tail[8 + t.body.len()..];
+                self.offset += 8 + t.body.len();
+            }
+            // stop after the first error
+            Err(_) => self.tail = &[],
+        }
+        Some(tag)
+    }
+}
+
+/// Used by the derived code for a field that is missing from the input.
+#[doc(hidden)]
+pub fn missing_field(name: &'static str) -> Error {
+    Error::missing_field(name)
+}
+
+const INT_KINDS: &[TypeKind] = &[
+    TypeKind::I8, TypeKind::U8, TypeKind::I16, TypeKind::U16, TypeKind::I32, TypeKind::U32,
+    TypeKind::I64, TypeKind::U64, TypeKind::Timestamp, TypeKind::Version,
+];
+
+fn write_int(writer: &mut Writer, code: Code, kind: TypeKind, v: i128) -> Result<(), Error> {
+    fn fit<T: TryFrom<i128>>(v: i128, kind: TypeKind) -> Result<T, Error> {
+        T::try_from(v).map_err(|_| Error::new(ErrorKind::WrongKind(kind)))
+    }
+    match kind {
+        TypeKind::I8 => writer.write_i8(code, fit(v, kind)?),
+        TypeKind::U8 => writer.write_u8(code, fit(v, kind)?),
+        TypeKind::I16 => writer.write_i16(code, fit(v, kind)?),
+        TypeKind::U16 => writer.write_u16(code, fit(v, kind)?),
+        TypeKind::I32 => writer.write_i32(code, fit(v, kind)?),
+        TypeKind::U32 | TypeKind::Timestamp | TypeKind::Version => writer.write_u32(code, fit(v, kind)?),
+        TypeKind::I64 => writer.write_i64(code, fit(v, kind)?),
+        TypeKind::U64 => writer.write_u64(code, fit(v, kind)?),
+        TypeKind::String | TypeKind::Container => return Err(Error::new(ErrorKind::WrongKind(kind))),
+    }.map(|_| ())
+}
+
+macro_rules! int_fields {
+    ($($ty:ty)*) => {
+        $(
+            impl EncodeField for $ty {
+                fn encode_field(&self, writer: &mut Writer, code: Code, kind: TypeKind) -> Result<(), Error> {
+                    write_int(writer, code, kind, i128::from(*self))
+                }
+            }
+
+            impl<'de> DecodeField<'de> for $ty {
+                fn decode_field(tag: Tag<'_, 'de>, kind: TypeKind) -> Result<Self, Error> {
+                    tag.int(kind)
+                }
+            }
+        )*
+    }
+}
+
+int_fields!(i8 u8 i16 u16 i32 u32 i64 u64);
+
+macro_rules! int_kinds {
+    ($($ty:ty)*) => {
+        $(
+            int_kinds!(@kinds $ty: I8 U8 I16 U16 I32 U32 I64 U64 Timestamp Version);
+        )*
+    };
+    (@kinds $ty:ty: $($kind:ident)*) => {
+        $(
+            impl EncodeAs<kinds::$kind> for $ty {}
+            impl DecodeAs<kinds::$kind> for $ty {}
+        )*
+    };
+}
+
+int_kinds!(i8 u8 i16 u16 i32 u32 i64 u64 Version);
+
+impl EncodeField for Version {
+    fn encode_field(&self, writer: &mut Writer, code: Code, kind: TypeKind) -> Result<(), Error> {
+        write_int(writer, code, kind, i128::from(u32::from(*self)))
+    }
+}
+
+impl<'de> DecodeField<'de> for Version {
+    fn decode_field(tag: Tag<'_, 'de>, kind: TypeKind) -> Result<Self, Error> {
+        tag.int::<u32>(kind).map(Version::from)
+    }
+}
+
+impl EncodeField for str {
+    fn encode_field(&self, writer: &mut Writer, code: Code, kind: TypeKind) -> Result<(), Error> {
+        if kind != TypeKind::String {
+            return Err(Error::new(ErrorKind::WrongKind(kind)));
+        }
+        writer.write_str(code, self).map(|_| ())
+    }
+}
+
+impl EncodeField for String {
+    fn encode_field(&self, writer: &mut Writer, code: Code, kind: TypeKind) -> Result<(), Error> {
+        self[..].encode_field(writer, code, kind)
+    }
+}
+
+impl<'a> EncodeField for Cow<'a, str> {
+    fn encode_field(&self, writer: &mut Writer, code: Code, kind: TypeKind) -> Result<(), Error> {
+        self[..].encode_field(writer, code, kind)
+    }
+}
+
+impl<T: EncodeField + ?Sized> EncodeField for &T {
+    fn encode_field(&self, writer: &mut Writer, code: Code, kind: TypeKind) -> Result<(), Error> {
+        (**self).encode_field(writer, code, kind)
+    }
+}
+
+impl EncodeAs<kinds::String> for str {}
+impl EncodeAs<kinds::String> for String {}
+impl<'a> EncodeAs<kinds::String> for Cow<'a, str> {}
+impl<K, T: EncodeAs<K> + ?Sized> EncodeAs<K> for &T {}
+
+impl DecodeAs<kinds::String> for String {}
+impl<'a> DecodeAs<kinds::String> for Cow<'a, str> {}
+impl DecodeAs<kinds::String> for &str {}
+
+impl<'de> DecodeField<'de> for String {
+    fn decode_field(tag: Tag<'_, 'de>, kind: TypeKind) -> Result<Self, Error> {
+        tag.str(kind).map(Cow::into_owned)
+    }
+}
+
+impl<'de> DecodeField<'de> for Cow<'de, str> {
+    fn decode_field(tag: Tag<'_, 'de>, kind: TypeKind) -> Result<Self, Error> {
+        tag.str(kind)
+    }
+}
+
+impl<'de> DecodeField<'de> for &'de str {
+    fn decode_field(tag: Tag<'_, 'de>, kind: TypeKind) -> Result<Self, Error> {
+        match tag.str(kind)? {
+            Cow::Borrowed(s) => Ok(s),
+            // StringPolicy::Lossy had to replace something, we can't borrow that
+            Cow::Owned(_) => Err(Error::new(ErrorKind::InvalidUtf8).at(tag.offset, tag.code)),
+        }
+    }
+}
+
+impl EncodeField for [u8] {
+    fn encode_field(&self, writer: &mut Writer, code: Code, _: TypeKind) -> Result<(), Error> {
+        writer.write_bytes(code, self).map(|_| ())
+    }
+}
+
+impl<'de> DecodeField<'de> for &'de [u8] {
+    fn decode_field(tag: Tag<'_, 'de>, _: TypeKind) -> Result<Self, Error> {
+        Ok(tag.body)
+    }
+}
+
+// the raw body works for any kind
+impl<K> EncodeAs<K> for [u8] {}
+impl<K> DecodeAs<K> for &[u8] {}
+
+impl<K, T: EncodeAs<K>> EncodeAs<K> for Option<T> {}
+impl<K, T: DecodeAs<K>> DecodeAs<K> for Option<T> {}
+impl<K, T: EncodeAs<K>> EncodeAs<K> for Vec<T> {}
+impl<K, T: DecodeAs<K>> DecodeAs<K> for Vec<T> {}
+
+impl<T: EncodeField> EncodeField for Option<T> {
+    fn encode_field(&self, writer: &mut Writer, code: Code, kind: TypeKind) -> Result<(), Error> {
+        match *self {
+            Some(ref v) => v.encode_field(writer, code, kind),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'de, T: DecodeField<'de>> DecodeField<'de> for Option<T> {
+    fn decode_field(tag: Tag<'_, 'de>, kind: TypeKind) -> Result<Self, Error> {
+        T::decode_field(tag, kind).map(Some)
+    }
+
+    fn missing() -> Option<Self> {
+        Some(None)
+    }
+}
+
+impl<T: EncodeField> EncodeField for Vec<T> {
+    fn encode_field(&self, writer: &mut Writer, code: Code, kind: TypeKind) -> Result<(), Error> {
+        self.iter().try_for_each(|v| v.encode_field(writer, code, kind))
+    }
+}
+
+impl<'de, T: DecodeField<'de>> DecodeField<'de> for Vec<T> {
+    fn decode_field(tag: Tag<'_, 'de>, kind: TypeKind) -> Result<Self, Error> {
+        T::decode_field(tag, kind).map(|v| vec![v])
+    }
+
+    fn merge(slot: &mut Option<Self>, tag: Tag<'_, 'de>, kind: TypeKind) -> Result<(), Error> {
+        let v = T::decode_field(tag, kind)?;
+        slot.get_or_insert_with(Vec::new).push(v);
+        Ok(())
+    }
+
+    fn missing() -> Option<Self> {
+        Some(Vec::new())
+    }
+}
@@ -1,7 +1,29 @@
 use serde::de;
 use serde::ser::{self, SerializeMap};
 
+use std::borrow::Cow;
 use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::Code;
+
+// Names used to smuggle timestamps and versions through serde. The
+// deserializer recognizes VALUE_TOKEN and hands out timestamps, versions and
+// raw invalid strings as enum variants, the serializer accepts timestamps and
+// versions as newtype structs.
+pub(crate) const VALUE_TOKEN: &str = "$dmap::DmapValue";
+pub(crate) const TIMESTAMP_TOKEN: &str = "$dmap::Timestamp";
+pub(crate) const VERSION_TOKEN: &str = "$dmap::Version";
+// used by `dmap_enum!`, the serializer sizes the discriminant by the key's type
+#[doc(hidden)]
+pub const ENUM_TOKEN: &str = "$dmap::Enum";
+
+#[derive(Deserialize)]
+pub(crate) enum TypedTag {
+    Timestamp,
+    Version,
+    InvalidString,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DmapItem<'a, 'k> {
@@ -19,7 +41,12 @@ pub enum DmapValue<'a, 'k> {
     U32(u32),
     I64(i64),
     U64(u64),
-    String(&'a str),
+    /// Seconds since the unix epoch.
+    Timestamp(u32),
+    Version(Version),
+    String(Cow<'a, str>),
+    /// A string that is not valid UTF-8, see `StringPolicy::Raw`.
+    InvalidString(&'a [u8]),
     Container(Vec<DmapItem<'a, 'k>>),
     Unknown(&'a [u8]),
 }
@@ -30,6 +57,152 @@ pub enum ItemName<'k> {
     Code([u8; 4]),
 }
 
+impl<'a, 'k> DmapItem<'a, 'k> {
+    /// The first child with the given name, see `DmapValue::get`.
+    pub fn get(&self, name: &str) -> Option<&DmapItem<'a, 'k>> {
+        self.value.get(name)
+    }
+
+    /// All children with the given name.
+    pub fn get_all<'s, 'n>(&'s self, name: &'n str) -> impl Iterator<Item = &'s DmapItem<'a, 'k>> + 'n
+        where 's: 'n
+    {
+        self.value.get_all(name)
+    }
+
+    /// Follows a `/` separated path of names, starting with this item's own
+    /// name: `item.path("daap.databasesongs/dmap.listing")`.
+    pub fn path(&self, path: &str) -> Option<&DmapItem<'a, 'k>> {
+        let mut names = path.split('/');
+        if !self.name.matches(names.next().unwrap()) {
+            return None;
+        }
+        names.try_fold(self, |item, name| item.get(name))
+    }
+}
+
+impl<'k> ItemName<'k> {
+    /// Compares with a content name, or with a code (like `mstt`) for tags
+    /// that are not in the dictionary.
+    pub fn matches(&self, name: &str) -> bool {
+        match *self {
+            ItemName::Name(n) => n == name,
+            ItemName::Code(c) => name.parse::<Code>() == Ok(Code(c)),
+        }
+    }
+}
+
+impl<'a, 'k> DmapValue<'a, 'k> {
+    /// The first child with the given name, `None` if there is none or this
+    /// is not a container.
+    pub fn get(&self, name: &str) -> Option<&DmapItem<'a, 'k>> {
+        self.get_all(name).next()
+    }
+
+    /// All children with the given name.
+    pub fn get_all<'s, 'n>(&'s self, name: &'n str) -> impl Iterator<Item = &'s DmapItem<'a, 'k>> + 'n
+        where 's: 'n
+    {
+        self.as_container().unwrap_or(&[]).iter().filter(move |i| i.name.matches(name))
+    }
+
+    /// Follows a `/` separated path of child names.
+    pub fn path(&self, path: &str) -> Option<&DmapItem<'a, 'k>> {
+        let mut names = path.split('/');
+        let first = self.get(names.next().unwrap())?;
+        names.try_fold(first, |item, name| item.get(name))
+    }
+
+    pub fn as_i8(&self) -> Option<i8> {
+        match *self { DmapValue::I8(x) => Some(x), _ => None }
+    }
+
+    pub fn as_u8(&self) -> Option<u8> {
+        match *self { DmapValue::U8(x) => Some(x), _ => None }
+    }
+
+    pub fn as_i16(&self) -> Option<i16> {
+        match *self { DmapValue::I16(x) => Some(x), _ => None }
+    }
+
+    pub fn as_u16(&self) -> Option<u16> {
+        match *self { DmapValue::U16(x) => Some(x), _ => None }
+    }
+
+    pub fn as_i32(&self) -> Option<i32> {
+        match *self { DmapValue::I32(x) => Some(x), _ => None }
+    }
+
+    pub fn as_u32(&self) -> Option<u32> {
+        match *self { DmapValue::U32(x) => Some(x), _ => None }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self { DmapValue::I64(x) => Some(x), _ => None }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self { DmapValue::U64(x) => Some(x), _ => None }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match *self { DmapValue::String(ref s) => Some(s), _ => None }
+    }
+
+    /// The body of a tag that is not in the dictionary.
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self { DmapValue::Unknown(b) => Some(b), _ => None }
+    }
+
+    pub fn as_container(&self) -> Option<&[DmapItem<'a, 'k>]> {
+        match *self { DmapValue::Container(ref c) => Some(c), _ => None }
+    }
+
+    pub fn as_system_time(&self) -> Option<SystemTime> {
+        match *self {
+            DmapValue::Timestamp(t) => Some(UNIX_EPOCH + Duration::from_secs(t.into())),
+            _ => None,
+        }
+    }
+
+    pub fn as_version(&self) -> Option<Version> {
+        match *self {
+            DmapValue::Version(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// A protocol version like `dmap.protocolversion`, sent as a u32 (2.0.6 is `0x00020006`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl From<u32> for Version {
+    fn from(v: u32) -> Version {
+        Version {
+            major: (v >> 16) as u16,
+            minor: (v >> 8) as u8,
+            patch: v as u8,
+        }
+    }
+}
+
+impl From<Version> for u32 {
+    fn from(v: Version) -> u32 {
+        (u32::from(v.major) << 16) | (u32::from(v.minor) << 8) | u32::from(v.patch)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
 impl<'de> de::Deserialize<'de> for DmapItem<'de, 'de> {
     fn deserialize<D>(deserializer: D) -> Result<DmapItem<'de, 'de>, D::Error>
         where D: de::Deserializer<'de>
@@ -134,13 +307,38 @@ impl<'de> de::Deserialize<'de> for DmapValue<'de, 'de> {
             }
 
             fn visit_borrowed_str<E>(self, value: &'de str) -> Result<Self::Value, E> {
-                Ok(DmapValue::String(value))
+                Ok(DmapValue::String(Cow::Borrowed(value)))
+            }
+
+            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> {
+                Ok(DmapValue::String(Cow::Owned(value.to_string())))
+            }
+
+            fn visit_string<E>(self, value: String) -> Result<Self::Value, E> {
+                Ok(DmapValue::String(Cow::Owned(value)))
             }
 
             fn visit_borrowed_bytes<E>(self, value: &'de [u8]) -> Result<Self::Value, E> {
                 Ok(DmapValue::Unknown(value))
             }
 
+            fn visit_enum<V>(self, data: V) -> Result<Self::Value, V::Error>
+                where V: de::EnumAccess<'de>
+            {
+                use serde::de::VariantAccess;
+                Ok(match data.variant()? {
+                    (TypedTag::Timestamp, v) => DmapValue::Timestamp(v.newtype_variant()?),
+                    (TypedTag::Version, v) => DmapValue::Version(v.newtype_variant::<u32>()?.into()),
+                    (TypedTag::InvalidString, v) => DmapValue::InvalidString(v.newtype_variant()?),
+                })
+            }
+
+            fn visit_newtype_struct<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+                where D: de::Deserializer<'de>
+            {
+                deserializer.deserialize_any(self)
+            }
+
             fn visit_map<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
                 where V: de::MapAccess<'de>
             {
@@ -157,7 +355,7 @@ impl<'de> de::Deserialize<'de> for DmapValue<'de, 'de> {
             }
         }
 
-        deserializer.deserialize_any(ValueVisitor)
+        deserializer.deserialize_newtype_struct(VALUE_TOKEN, ValueVisitor)
     }
 }
 
@@ -195,8 +393,10 @@ impl<'a, 'k> ser::Serialize for DmapValue<'a, 'k> {
             DmapValue::U32(x) => serializer.serialize_u32(x),
             DmapValue::I64(x) => serializer.serialize_i64(x),
             DmapValue::U64(x) => serializer.serialize_u64(x),
-            DmapValue::String(s) => serializer.serialize_str(s),
-            DmapValue::Unknown(b) => serializer.serialize_bytes(b),
+            DmapValue::Timestamp(x) => serializer.serialize_newtype_struct(TIMESTAMP_TOKEN, &x),
+            DmapValue::Version(v) => serializer.serialize_newtype_struct(VERSION_TOKEN, &u32::from(v)),
+            DmapValue::String(ref s) => serializer.serialize_str(s),
+            DmapValue::InvalidString(b) | DmapValue::Unknown(b) => serializer.serialize_bytes(b),
             DmapValue::Container(ref c) => {
                 let mut map = serializer.serialize_map(Some(c.len()))?;
                 for e in c {
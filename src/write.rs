@@ -0,0 +1,314 @@
+//! Serializing to a `std::io::Write`.
+//!
+//! DMAP puts the length of a container in front of its body, so `to_vec`
+//! writes a placeholder and patches it once the container is done. A socket
+//! can't be patched, so `to_writer` serializes the value twice instead: the
+//! first pass only measures the containers, the second one writes everything
+//! out with the lengths known up front. Memory use is one `u32` per
+//! container no matter how big the strings in it are.
+//!
+//! Both passes have to see the same data, so `Serialize` impls must be
+//! deterministic (iterating a `HashMap` twice is fine, it doesn't change in
+//! between). If a container comes out a different size the second time,
+//! serializing fails, but what was written before that is already out.
+//! Writes are small, wrap sockets and files in a `BufWriter`.
+
+use serde::ser::{self, Serialize, SerializeMap};
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{Parser, Error};
+use ser::{discriminant, key_code, unsupported};
+use value;
+
+use std::io::{self, Write};
+use std::mem;
+
+pub fn to_writer<'a, 'k, W, T>(parser: &'a Parser<'k>, writer: W, value: &T) -> Result<(), Error>
+    where W: Write, T: Serialize + ?Sized
+{
+    let mut sizer = StreamSerializer::new(parser, io::sink(), Vec::new(), true);
+    value.serialize(&mut sizer)?;
+
+    let mut serializer = StreamSerializer::new(parser, writer, sizer.sizes, false);
+    value.serialize(&mut serializer)?;
+    if serializer.next != serializer.sizes.len() {
+        return Err(changed());
+    }
+    serializer.writer.flush().map_err(Error::io)
+}
+
+fn changed() -> Error {
+    ser::Error::custom("value serialized differently on the second pass")
+}
+
+struct StreamSerializer<'a, 'k: 'a, W> {
+    parser: &'a Parser<'k>,
+    writer: W,
+    /// The code of the current key. It is only written together with the
+    /// value, so `None` values can still be skipped.
+    pending: Option<[u8; 4]>,
+    /// The next `i64` is a `dmap_enum!` discriminant.
+    discriminant: bool,
+    /// Container lengths, in the order the containers are opened.
+    sizes: Vec<u32>,
+    /// In the sizing pass this is where `sizes` gets filled in, in the
+    /// writing pass the index of the next length to use.
+    next: usize,
+    sizing: bool,
+    written: usize,
+}
+
+impl<'a, 'k, W: Write> StreamSerializer<'a, 'k, W> {
+    fn new(parser: &'a Parser<'k>, writer: W, sizes: Vec<u32>, sizing: bool) -> StreamSerializer<'a, 'k, W> {
+        StreamSerializer {
+            parser,
+            writer,
+            pending: None,
+            discriminant: false,
+            sizes,
+            next: 0,
+            sizing,
+            written: 0,
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.written += buf.len();
+        self.writer.write_all(buf).map_err(|e| Error::io(e).at_offset(self.written - buf.len()))
+    }
+
+    fn header(&mut self, len: u32, what: &'static str) -> Result<(), Error> {
+        let code = self.pending.take().ok_or_else(|| unsupported(what))?;
+        let mut buf = [0; 8];
+        buf[..4].copy_from_slice(&code);
+        BigEndian::write_u32(&mut buf[4..], len);
+        self.write(&buf)
+    }
+
+    fn leaf(&mut self, body: &[u8]) -> Result<(), Error> {
+        self.header(body.len() as u32, "value outside of a map")?;
+        self.write(body)
+    }
+
+    fn discriminant(&mut self, v: i64) -> Result<(), Error> {
+        let code = self.pending.ok_or_else(|| unsupported("enum outside of a map"))?;
+        let body = discriminant(self.parser, code, v)?;
+        self.leaf(&body)
+    }
+}
+
+impl<'a, 'k: 'a, 'b, W: Write> ser::Serializer for &'b mut StreamSerializer<'a, 'k, W> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = SeqSerializer<'a, 'k, 'b, W>;
+    type SerializeTuple = ser::Impossible<Self::Ok, Self::Error>;
+    type SerializeTupleStruct = ser::Impossible<Self::Ok, Self::Error>;
+    type SerializeTupleVariant = ser::Impossible<Self::Ok, Self::Error>;
+    type SerializeMap = MapSerializer<'a, 'k, 'b, W>;
+    type SerializeStruct = MapSerializer<'a, 'k, 'b, W>;
+    type SerializeStructVariant = ser::Impossible<Self::Ok, Self::Error>;
+
+    fn serialize_bool(self, _: bool) -> Result<(), Error> { Err(unsupported("bool")) }
+    fn serialize_f32(self, _: f32) -> Result<(), Error> { Err(unsupported("f32")) }
+    fn serialize_f64(self, _: f64) -> Result<(), Error> { Err(unsupported("f64")) }
+    fn serialize_char(self, _: char) -> Result<(), Error> { Err(unsupported("char")) }
+    fn serialize_unit(self) -> Result<(), Error> { Err(unsupported("unit")) }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> { Err(unsupported("unit struct")) }
+    fn serialize_newtype_variant<T: ?Sized>(self, _: &'static str, _: u32, _: &'static str, _: &T) -> Result<(), Error> { Err(unsupported("enum")) }
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Error> { Err(unsupported("tuple")) }
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeTupleStruct, Error> { Err(unsupported("tuple struct")) }
+    fn serialize_tuple_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeTupleVariant, Error> { Err(unsupported("enum")) }
+    fn serialize_struct_variant(self, _: &'static str, _: u32, _: &'static str, _: usize) -> Result<Self::SerializeStructVariant, Error> { Err(unsupported("enum")) }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        // every element gets the key's code
+        let code = self.pending.take().ok_or_else(|| unsupported("sequence outside of a map"))?;
+        Ok(SeqSerializer { parent: self, code })
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        // no pending key means this is the root node, which has no header
+        if self.pending.is_none() {
+            return Ok(MapSerializer { parent: self, size: None });
+        }
+
+        let index = if self.sizing {
+            self.sizes.push(0);
+            self.header(0, "map outside of a map")?;
+            self.sizes.len() - 1
+        } else {
+            let len = match self.sizes.get(self.next) {
+                Some(&len) => len,
+                None => return Err(changed()),
+            };
+            self.next += 1;
+            self.header(len, "map outside of a map")?;
+            self.next - 1
+        };
+        let start = self.written;
+        Ok(MapSerializer { parent: self, size: Some((index, start)) })
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self::SerializeStruct, Error> {
+        self.serialize_map(None)
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        // the key was never written, so just forget about it
+        self.pending.take().map(|_| ()).ok_or_else(|| unsupported("none outside of a map"))
+    }
+
+    fn serialize_newtype_struct<T>(self, name: &'static str, value: &T) -> Result<(), Error>
+        where T: Serialize + ?Sized
+    {
+        if name == value::TIMESTAMP_TOKEN || name == value::VERSION_TOKEN {
+            value.serialize(self)
+        } else if name == value::ENUM_TOKEN {
+            self.discriminant = true;
+            let result = value.serialize(&mut *self);
+            self.discriminant = false;
+            result
+        } else {
+            Err(unsupported("newtype struct"))
+        }
+    }
+
+    fn serialize_some<T>(self, t: &T) -> Result<(), Error>
+        where T: Serialize + ?Sized
+    {
+        t.serialize(self)
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, index: u32, _: &'static str) -> Result<(), Error> {
+        self.discriminant(i64::from(index))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.leaf(v)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.leaf(v.as_bytes())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.leaf(&[v as u8])
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.leaf(&[v])
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        let mut buf = [0; 2];
+        BigEndian::write_i16(&mut buf, v);
+        self.leaf(&buf)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        let mut buf = [0; 2];
+        BigEndian::write_u16(&mut buf, v);
+        self.leaf(&buf)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        let mut buf = [0; 4];
+        BigEndian::write_i32(&mut buf, v);
+        self.leaf(&buf)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        let mut buf = [0; 4];
+        BigEndian::write_u32(&mut buf, v);
+        self.leaf(&buf)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        if mem::replace(&mut self.discriminant, false) {
+            return self.discriminant(v);
+        }
+        let mut buf = [0; 8];
+        BigEndian::write_i64(&mut buf, v);
+        self.leaf(&buf)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        let mut buf = [0; 8];
+        BigEndian::write_u64(&mut buf, v);
+        self.leaf(&buf)
+    }
+}
+
+struct SeqSerializer<'a: 'b, 'k: 'a, 'b, W: 'b> {
+    parent: &'b mut StreamSerializer<'a, 'k, W>,
+    code: [u8; 4],
+}
+
+impl<'a: 'b, 'k: 'a, 'b, W: Write> ser::SerializeSeq for SeqSerializer<'a, 'k, 'b, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
+        where T: Serialize + ?Sized,
+    {
+        self.parent.pending = Some(self.code);
+        value.serialize(&mut *self.parent)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+}
+
+struct MapSerializer<'a: 'b, 'k: 'a, 'b, W: 'b> {
+    parent: &'b mut StreamSerializer<'a, 'k, W>,
+    /// Index into `sizes` and the offset of the body, `None` for the root.
+    size: Option<(usize, usize)>,
+}
+
+impl<'a: 'b, 'k: 'a, 'b, W: Write> ser::SerializeMap for MapSerializer<'a, 'k, 'b, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T>(&mut self, value: &T) -> Result<(), Self::Error>
+        where T: Serialize + ?Sized
+    {
+        self.parent.pending = Some(key_code(self.parent.parser, value)?);
+        Ok(())
+    }
+
+    fn serialize_value<T>(&mut self, value: &T) -> Result<(), Self::Error>
+        where T: Serialize + ?Sized
+    {
+        value.serialize(&mut *self.parent)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        if let Some((index, start)) = self.size {
+            let len = (self.parent.written - start) as u32;
+            if self.parent.sizing {
+                self.parent.sizes[index] = len;
+            } else if len != self.parent.sizes[index] {
+                // the header with the old length is already out
+                return Err(changed());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a: 'b, 'k: 'a, 'b, W: Write> ser::SerializeStruct for MapSerializer<'a, 'k, 'b, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, name: &'static str, value: &T) -> Result<(), Self::Error>
+        where T: Serialize + ?Sized
+    {
+        self.serialize_key(name)?;
+        self.serialize_value(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        SerializeMap::end(self)
+    }
+}
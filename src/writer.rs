@@ -0,0 +1,158 @@
+//! A low level push writer.
+//!
+//! `Writer` emits tags by code, without going through serde, and fills in
+//! container lengths as the containers are closed. When it is given a
+//! `Parser` every tag is checked against the dictionary.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{Parser, Code, TypeKind, Error, ErrorKind};
+
+pub struct Writer<'a, 'k: 'a> {
+    parser: Option<&'a Parser<'k>>,
+    output: Vec<u8>,
+    /// Offsets of the open containers' headers, innermost last.
+    open: Vec<usize>,
+}
+
+impl<'a, 'k: 'a> Default for Writer<'a, 'k> {
+    fn default() -> Writer<'a, 'k> {
+        Writer::new()
+    }
+}
+
+impl<'a, 'k: 'a> Writer<'a, 'k> {
+    /// A writer that accepts any code.
+    pub fn new() -> Writer<'a, 'k> {
+        Writer {
+            parser: None,
+            output: Vec::new(),
+            open: Vec::new(),
+        }
+    }
+
+    /// A writer that only accepts codes from `parser`'s dictionary, with
+    /// matching types.
+    pub fn with_parser(parser: &'a Parser<'k>) -> Writer<'a, 'k> {
+        Writer { parser: Some(parser), ..Writer::new() }
+    }
+
+    /// Number of containers currently open.
+    pub fn depth(&self) -> usize {
+        self.open.len()
+    }
+
+    pub fn begin_container<C: Into<Code>>(&mut self, code: C) -> Result<&mut Self, Error> {
+        let code = code.into().0;
+        self.check(code, &[TypeKind::Container])?;
+        self.open.push(self.output.len());
+        self.header(code, 0);
+        Ok(self)
+    }
+
+    pub fn end_container(&mut self) -> Result<&mut Self, Error> {
+        let start = match self.open.pop() {
+            Some(x) => x,
+            None => return Err(Error::new(ErrorKind::UnbalancedContainer).at_offset(self.output.len())),
+        };
+        let len = (self.output.len() - start - 8) as u32;
+        BigEndian::write_u32(&mut self.output[start + 4..start + 8], len);
+        Ok(self)
+    }
+
+    pub fn write_i8<C: Into<Code>>(&mut self, code: C, v: i8) -> Result<&mut Self, Error> {
+        self.leaf(code.into().0, &[TypeKind::I8], &[v as u8])
+    }
+
+    pub fn write_u8<C: Into<Code>>(&mut self, code: C, v: u8) -> Result<&mut Self, Error> {
+        self.leaf(code.into().0, &[TypeKind::U8], &[v])
+    }
+
+    pub fn write_i16<C: Into<Code>>(&mut self, code: C, v: i16) -> Result<&mut Self, Error> {
+        let mut buf = [0; 2];
+        BigEndian::write_i16(&mut buf, v);
+        self.leaf(code.into().0, &[TypeKind::I16], &buf)
+    }
+
+    pub fn write_u16<C: Into<Code>>(&mut self, code: C, v: u16) -> Result<&mut Self, Error> {
+        let mut buf = [0; 2];
+        BigEndian::write_u16(&mut buf, v);
+        self.leaf(code.into().0, &[TypeKind::U16], &buf)
+    }
+
+    pub fn write_i32<C: Into<Code>>(&mut self, code: C, v: i32) -> Result<&mut Self, Error> {
+        let mut buf = [0; 4];
+        BigEndian::write_i32(&mut buf, v);
+        self.leaf(code.into().0, &[TypeKind::I32], &buf)
+    }
+
+    /// Also accepts timestamp and version tags, which are sent as u32.
+    pub fn write_u32<C: Into<Code>>(&mut self, code: C, v: u32) -> Result<&mut Self, Error> {
+        let mut buf = [0; 4];
+        BigEndian::write_u32(&mut buf, v);
+        self.leaf(code.into().0, &[TypeKind::U32, TypeKind::Timestamp, TypeKind::Version], &buf)
+    }
+
+    pub fn write_i64<C: Into<Code>>(&mut self, code: C, v: i64) -> Result<&mut Self, Error> {
+        let mut buf = [0; 8];
+        BigEndian::write_i64(&mut buf, v);
+        self.leaf(code.into().0, &[TypeKind::I64], &buf)
+    }
+
+    pub fn write_u64<C: Into<Code>>(&mut self, code: C, v: u64) -> Result<&mut Self, Error> {
+        let mut buf = [0; 8];
+        BigEndian::write_u64(&mut buf, v);
+        self.leaf(code.into().0, &[TypeKind::U64], &buf)
+    }
+
+    pub fn write_str<C: Into<Code>>(&mut self, code: C, v: &str) -> Result<&mut Self, Error> {
+        self.leaf(code.into().0, &[TypeKind::String], v.as_bytes())
+    }
+
+    /// Writes a raw body. This is never checked against the dictionary, so
+    /// it also works for codes the parser doesn't know.
+    pub fn write_bytes<C: Into<Code>>(&mut self, code: C, v: &[u8]) -> Result<&mut Self, Error> {
+        self.header(code.into().0, v.len() as u32);
+        self.output.extend_from_slice(v);
+        Ok(self)
+    }
+
+    /// Returns the encoded data, failing if a container is still open.
+    pub fn finish(self) -> Result<Vec<u8>, Error> {
+        match self.open.last() {
+            Some(&start) => {
+                let mut code = [0; 4];
+                code.copy_from_slice(&self.output[start..start + 4]);
+                Err(Error::new(ErrorKind::UnbalancedContainer).at(start, code))
+            }
+            None => Ok(self.output),
+        }
+    }
+
+    fn check(&self, code: [u8; 4], kinds: &[TypeKind]) -> Result<(), Error> {
+        let parser = match self.parser {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        let offset = self.output.len();
+        match parser.lookup_code(code) {
+            Some(c) if kinds.contains(&c.kind) => Ok(()),
+            Some(c) => Err(Error::new(ErrorKind::WrongKind(c.kind)).at(offset, code)),
+            None => Err(Error::new(ErrorKind::UnknownTag).at(offset, code)),
+        }
+    }
+
+    fn header(&mut self, code: [u8; 4], len: u32) {
+        let mut buf = [0; 8];
+        buf[..4].copy_from_slice(&code);
+        BigEndian::write_u32(&mut buf[4..], len);
+        self.output.extend_from_slice(&buf);
+    }
+
+    fn leaf(&mut self, code: [u8; 4], kinds: &[TypeKind], body: &[u8]) -> Result<&mut Self, Error> {
+        self.check(code, kinds)?;
+        self.header(code, body.len() as u32);
+        self.output.extend_from_slice(body);
+        Ok(self)
+    }
+}
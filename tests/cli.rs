@@ -0,0 +1,60 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn dmap(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dmap"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn dump() {
+    let out = dmap(&["dump", "testdata/login.bin"], b"");
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "\
+mlog dmap.loginresponse container [24]
+  mstt dmap.status i32 [4] 200
+  mlid dmap.sessionid i32 [4] 961664443
+");
+}
+
+#[test]
+fn json_roundtrip() {
+    for file in &["testdata/login.bin", "testdata/server-info.bin", "testdata/content-codes.bin"] {
+        let json = dmap(&["to-json", file], b"");
+        assert!(json.status.success(), "{}", file);
+        let back = dmap(&["from-json", "-"], &json.stdout);
+        assert!(back.status.success(), "{}", file);
+        assert_eq!(back.stdout, std::fs::read(file).unwrap(), "{}", file);
+    }
+}
+
+#[test]
+fn errors() {
+    let out = dmap(&["frobnicate", "testdata/login.bin"], b"");
+    assert_eq!(out.status.code(), Some(2));
+    let out = dmap(&["from-json", "-"], b"{");
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8(out.stderr).unwrap().starts_with("dmap: "));
+}
+
+#[test]
+fn closed_stdout() {
+    // like `dmap dump ... | head -0`: the reader is gone before we write
+    let mut child = Command::new(env!("CARGO_BIN_EXE_dmap"))
+        .args(["dump", "testdata/content-codes.bin"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdout.take());
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(out.stderr, b"");
+}
@@ -0,0 +1,128 @@
+extern crate dmap;
+#[macro_use] extern crate dmap_derive;
+extern crate trybuild;
+
+use dmap::typed::{DmapDecode, DmapEncode};
+use dmap::{DecodeOptions, DmapItem, DmapValue, ErrorKind, Limits, Parser, Version};
+
+use std::borrow::Cow;
+
+#[derive(Debug, PartialEq, DmapEncode, DmapDecode)]
+#[dmap(code = "mlog")]
+struct Login {
+    #[dmap(code = "mstt", kind = "i32")]
+    status: i32,
+    #[dmap(code = "mlid", kind = "i32")]
+    session_id: u32,
+}
+
+#[derive(Debug, PartialEq, DmapEncode, DmapDecode)]
+#[dmap(code = "mlit")]
+struct Item<'a> {
+    #[dmap(code = "miid", kind = "i32")]
+    id: u32,
+    #[dmap(code = "minm", kind = "string")]
+    name: Cow<'a, str>,
+    #[dmap(code = "asal", kind = "string")]
+    album: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq, DmapEncode, DmapDecode)]
+#[dmap(code = "mlcl")]
+struct Listing<'a> {
+    #[dmap(code = "mlit", kind = "container")]
+    items: Vec<Item<'a>>,
+}
+
+#[derive(Debug, PartialEq, DmapEncode, DmapDecode)]
+#[dmap(code = "apso")]
+struct Playlist<'a> {
+    #[dmap(code = "mstt", kind = "i32")]
+    status: i32,
+    #[dmap(code = "mpro", kind = "version")]
+    version: Version,
+    #[dmap(code = "mlcl", kind = "container")]
+    listing: Listing<'a>,
+    #[dmap(skip)]
+    selected: bool,
+}
+
+#[test]
+fn login() {
+    let data = include_bytes!("../testdata/login.bin");
+    let login = Login::decode(&Parser::empty(), data).unwrap();
+    assert_eq!(login, Login { status: 200, session_id: 0x3951d5bb });
+    assert_eq!(login.encode().unwrap(), &data[..]);
+}
+
+#[test]
+fn roundtrip() {
+    let playlist = Playlist {
+        status: 200,
+        version: Version { major: 2, minor: 0, patch: 1 },
+        listing: Listing {
+            items: vec![
+                Item { id: 1, name: "Song".into(), album: Some("Album") },
+                Item { id: 2, name: "Other".into(), album: None },
+            ],
+        },
+        selected: false,
+    };
+    let data = playlist.encode().unwrap();
+    assert_eq!(Playlist::decode(&Parser::empty(), &data).unwrap(), playlist);
+
+    // the same bytes as seen through the dictionary
+    let parser = Parser::builtin();
+    let item: DmapItem = dmap::from_slice(&parser, &data).unwrap();
+    match item.path("daap.playlistsongs/dmap.listing").unwrap().value {
+        DmapValue::Container(ref items) => assert_eq!(items.len(), 2),
+        ref v => panic!("{:?}", v),
+    }
+    match item.get("dmap.status").unwrap().value {
+        DmapValue::I32(200) => {}
+        ref v => panic!("{:?}", v),
+    }
+    assert_eq!(dmap::to_vec(&parser, &item).unwrap(), data);
+}
+
+#[test]
+fn untrusted() {
+    let names: Vec<String> = (0..5000).map(|i| format!("Song number {}", i)).collect();
+    let listing = Listing {
+        items: names.iter().enumerate().map(|(i, name)| Item { id: i as u32, name: name[..].into(), album: None }).collect(),
+    };
+    let data = listing.encode().unwrap();
+    assert!(data.len() > 64 * 1024);
+
+    // the body limit is for leaf tags, not for the containers around them
+    let mut parser = Parser::empty();
+    parser.set_decode_options(DecodeOptions { limits: Limits::untrusted(), ..DecodeOptions::default() });
+    assert_eq!(Listing::decode(&parser, &data).unwrap(), listing);
+}
+
+#[test]
+fn errors() {
+    let parser = Parser::empty();
+    let login = include_bytes!("../testdata/login.bin");
+
+    let err = Item::decode(&parser, login).unwrap_err();
+    assert_eq!(err.code(), Some(*b"mlog"));
+
+    // mstt without mlid
+    let err = Login::decode(&parser, b"mlog\0\0\0\x0cmstt\0\0\0\x04\0\0\0\xc8").unwrap_err();
+    assert_eq!(*err.kind(), ErrorKind::Message("missing field `session_id`".to_string()));
+
+    // a 3 byte mstt
+    let err = Login::decode(&parser, b"mlog\0\0\0\x0bmstt\0\0\0\x03abc").unwrap_err();
+    assert_eq!(*err.kind(), ErrorKind::InvalidLength { expected: 4, found: 3 });
+    assert_eq!((err.offset(), err.code()), (Some(8), Some(*b"mstt")));
+}
+
+// The expected output is rustc's exact wording, which changes between
+// releases. Run with `cargo test -- --ignored` on the toolchain that wrote
+// tests/ui/*.stderr (1.95), and refresh them with TRYBUILD=overwrite.
+#[test]
+#[ignore]
+fn compile_errors() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
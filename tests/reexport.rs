@@ -0,0 +1,23 @@
+//! The derives as re-exported by `dmap` with the `derive` feature.
+
+extern crate dmap;
+
+use dmap::typed::{DmapDecode, DmapEncode};
+use dmap::Parser;
+
+#[derive(Debug, PartialEq, dmap::DmapEncode, dmap::DmapDecode)]
+#[dmap(code = "mlog")]
+struct Login {
+    #[dmap(code = "mstt", kind = "i32")]
+    status: i32,
+    #[dmap(code = "mlid", kind = "i32")]
+    session_id: u32,
+}
+
+#[test]
+fn login() {
+    let data = include_bytes!("../testdata/login.bin");
+    let login = Login::decode(&Parser::empty(), data).unwrap();
+    assert_eq!(login, Login { status: 200, session_id: 0x3951d5bb });
+    assert_eq!(login.encode().unwrap(), &data[..]);
+}
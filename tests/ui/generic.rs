@@ -0,0 +1,11 @@
+extern crate dmap;
+#[macro_use] extern crate dmap_derive;
+
+#[derive(DmapDecode)]
+#[dmap(code = "mlit")]
+struct Item<T> {
+    #[dmap(code = "miid", kind = "i32")]
+    id: T,
+}
+
+fn main() {}
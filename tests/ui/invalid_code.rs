@@ -0,0 +1,11 @@
+extern crate dmap;
+#[macro_use] extern crate dmap_derive;
+
+#[derive(DmapEncode)]
+#[dmap(code = "mlogx")]
+struct Login {
+    #[dmap(code = "mstt", kind = "i32")]
+    status: i32,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+extern crate dmap;
+#[macro_use] extern crate dmap_derive;
+
+#[derive(DmapEncode)]
+#[dmap(code = "mlit")]
+struct Item {
+    #[dmap(code = "minm", kind = "i32")]
+    name: String,
+}
+
+#[derive(DmapDecode)]
+#[dmap(code = "mlog")]
+struct Login {
+    #[dmap(code = "mstt", kind = "container")]
+    status: Option<i32>,
+}
+
+fn main() {}
@@ -0,0 +1,13 @@
+extern crate dmap;
+#[macro_use] extern crate dmap_derive;
+
+#[derive(DmapDecode)]
+#[dmap(code = "mlit")]
+struct Item<'a, 'b> {
+    #[dmap(code = "minm", kind = "string")]
+    name: &'a str,
+    #[dmap(code = "asal", kind = "string")]
+    album: &'b str,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+extern crate dmap;
+#[macro_use] extern crate dmap_derive;
+
+#[derive(DmapEncode)]
+#[dmap(code = "mlog", kind = "container")]
+struct Login {
+    #[dmap(code = "mstt", kind = "i32")]
+    status: i32,
+}
+
+#[derive(DmapEncode)]
+#[dmap(code = "mlog", skip)]
+struct Status {
+    #[dmap(code = "mstt", kind = "i32")]
+    status: i32,
+}
+
+fn main() {}